@@ -1,13 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use anyhow::Context;
-use bytes::Bytes;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
 
-use crate::{crawl::ParsedBook, util::ApiClient, BookComplete};
+use crate::{
+    cache::{self, HttpCache},
+    crawl::ParsedBook,
+    manifest::ManifestEntry,
+    output, record,
+    select::{self, PageRanges},
+    sniff::Expected,
+    util::ApiClient,
+    BookComplete,
+};
 
 lazy_static! {
     static ref BOOK_HTML_TITLE_REGEX: Regex = Regex::new(r#"action='([^']+)'"#).unwrap();
@@ -18,6 +25,14 @@ lazy_static! {
     static ref BOOK_HTML_PAGE_REGEX: Regex = Regex::new(r#"\[(\d+),(\d+)\]"#).unwrap();
     static ref IMG_REGEX: Regex = Regex::new(r#"xlink:href="(img/[^"]+)"#).unwrap();
     static ref SHADE_REGEX: Regex = Regex::new(r#"xlink:href="(shade/[^"]+)"#).unwrap();
+    static ref BOOK_HTML_TOC_REGEX: Regex = Regex::new(
+        r#"(?is)<a[^>]*?(?:href|onclick)=["'][^"']*?(?:gotoPage|goToPage|jumpToPage)\(\s*["']?(\d+)["']?\s*\)[^"']*["'][^>]*>(.*?)</a>"#
+    )
+    .unwrap();
+    static ref BOOK_HTML_PAGE_LABELS_REGEX: Regex =
+        Regex::new(r#"(?s)pageLabels\s*[=:]\s*\[(.*?)\]"#).unwrap();
+    static ref HTML_TAG_REGEX: Regex = Regex::new(r#"<[^>]*>"#).unwrap();
+    static ref SVG_TEXT_END_REGEX: Regex = Regex::new(r#"</text\s*>"#).unwrap();
 }
 
 pub async fn do_book_form_dance(
@@ -27,7 +42,7 @@ pub async fn do_book_form_dance(
     let mut url = url.to_string();
 
     // Part 1: The html-form dance
-    let response = client.get(&url).send().await?;
+    let response = record::send(client.get(&url)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -57,8 +72,7 @@ pub async fn do_book_form_dance(
             form.insert(capture[1].to_string(), capture[2].to_string());
         });
 
-    dbg!(&url);
-    let response = client.post(&url).form(&form).send().await?;
+    let response = record::send(client.post(&url).form(&form)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -88,8 +102,7 @@ pub async fn do_book_form_dance(
             form.insert(capture[1].to_string(), capture[2].to_string());
         });
 
-    dbg!(&url);
-    let response = client.post(&url).form(&form).send().await?;
+    let response = record::send(client.post(&url).form(&form)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -118,6 +131,19 @@ pub struct BookMeta {
     pub publisher_mail: String,
     // pub viewport: String,
     pub page_sizes: Vec<[u16; 2]>,
+    /// The table of contents of the viewer (empty if the book has none)
+    #[serde(default)]
+    pub toc: Vec<TocEntry>,
+    /// The printed page labels (e.g. "IV" or "12"), indexed by page
+    #[serde(default)]
+    pub page_labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// An entry of the table of contents, pointing at a (1-based) page
+pub struct TocEntry {
+    pub title: String,
+    pub page: usize,
 }
 
 pub fn extract_metadata_from_initial_html(initial_book_html: &str) -> anyhow::Result<BookMeta> {
@@ -176,11 +202,48 @@ pub fn extract_metadata_from_initial_html(initial_book_html: &str) -> anyhow::Re
         //     .context("Missing viewport")?
         //     .to_string(),
         page_sizes,
+        toc: extract_toc(initial_book_html),
+        page_labels: extract_page_labels(initial_book_html),
     };
 
     Ok(book_meta)
 }
 
+/// Extracts the table of contents from the navigation of the viewer.
+///
+/// The viewer links its chapters using `gotoPage(x)` calls, so every such
+/// link is treated as a TOC entry (in document order).
+pub fn extract_toc(initial_book_html: &str) -> Vec<TocEntry> {
+    BOOK_HTML_TOC_REGEX
+        .captures_iter(initial_book_html)
+        .filter_map(|capture| {
+            let page = capture[1].parse::<usize>().ok()?;
+            let title = HTML_TAG_REGEX.replace_all(&capture[2], "");
+            let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+
+            (!title.is_empty()).then_some(TocEntry { title, page })
+        })
+        .collect()
+}
+
+/// Extracts the printed page labels (`pageLabels = ["I", "II", "1", ...]`).
+pub fn extract_page_labels(initial_book_html: &str) -> Vec<String> {
+    let Some(capture) = BOOK_HTML_PAGE_LABELS_REGEX.captures(initial_book_html) else {
+        return Vec::new();
+    };
+
+    capture[1]
+        .split(',')
+        .map(|label| {
+            label
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .filter(|label| !label.is_empty())
+        .collect()
+}
+
 pub enum Version {
     Old,
     New,
@@ -195,8 +258,7 @@ pub async fn do_version_check(
     ;
     let url = url.to_string() + "1/1.svg";
 
-    dbg!(&url);
-    let response = client.get(&url).send().await?;
+    let response = record::send(client.get(&url)).await?;
 
     if !response.status().is_success() {
         // return Err(anyhow::anyhow!(
@@ -204,9 +266,6 @@ pub async fn do_version_check(
         //     response.status()
         // ));
 
-        dbg!(response.status());
-        dbg!(response.text().await?);
-
         return Ok(Version::Old);
     };
 
@@ -220,34 +279,42 @@ pub async fn do_version_check(
 }
 
 pub async fn do_download(
-    ApiClient(client, _): &ApiClient,
+    c: &ApiClient,
+    cache: &mut HttpCache,
     url: &str,
     book_meta: &BookMeta,
+    pages: Option<&PageRanges>,
     save_path: impl AsRef<std::path::Path>,
-) -> anyhow::Result<()> {
-    // Append idx/idx.svg to the url, where idx is the page index
-    for page in 1..=book_meta.page_sizes.len() {
-        let url = format!("{url}{page}/{page}.svg");
-
-        dbg!(&url);
-        let response = client.get(&url).send().await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Got non-success status code: {}",
-                response.status()
-            ));
-        };
+    journal: &std::path::Path,
+) -> anyhow::Result<Vec<ManifestEntry>> {
+    let selected = (1..=book_meta.page_sizes.len())
+        .filter(|page| select::page_selected(pages, *page))
+        .collect::<Vec<_>>();
 
-        let text = response.text().await?;
-
-        let mut path = save_path.as_ref().to_path_buf();
-        path.push(format!("{page}.svg"));
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(text.as_bytes()).await?;
-    }
-
-    Ok(())
+    // Append idx/idx.svg to the url, where idx is the page index
+    let files = selected
+        .iter()
+        .map(|page| {
+            let url = format!("{url}{page}/{page}.svg");
+
+            let mut path = save_path.as_ref().to_path_buf();
+            path.push(format!("{page}.svg"));
+
+            (url, path)
+        })
+        .collect();
+
+    cache::fetch_all(c, cache, files, Expected::Page, journal, |i, entry| {
+        output::event(
+            "page_downloaded",
+            serde_json::json!({
+                "page": selected[i],
+                "pages": book_meta.page_sizes.len(),
+                "file": entry,
+            }),
+        );
+    })
+    .await
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -265,7 +332,7 @@ pub enum ImgType {
 }
 
 pub async fn get_img_urls(
-    ApiClient(client, _): &ApiClient,
+    ApiClient(_client, _): &ApiClient,
     // book_meta: &BookMeta,
     // version: &Version,
     // book: &ParsedBook,
@@ -273,7 +340,7 @@ pub async fn get_img_urls(
     svg_path: impl AsRef<std::path::Path>,
     // img_path: impl AsRef<std::path::Path>,
 ) -> anyhow::Result<Vec<Img>> {
-    let img_base_url = "https://a.digi4school.at/ebook/".to_string() + &book_id + "/";
+    let img_base_url = "https://a.digi4school.at/ebook/".to_string() + book_id + "/";
     // let img_base_url = "https://a.digi4school.at/ebook/".to_string() +  + "/";
 
     // Image urls containing the absolute path to the image
@@ -286,6 +353,11 @@ pub async fn get_img_urls(
         // Read the file
         let file = file?;
         let path = file.path();
+
+        // Skip anything else (e.g. files still being written)
+        if path.extension().is_none_or(|extension| extension != "svg") {
+            continue;
+        }
         let text = tokio::fs::read_to_string(path.clone()).await?;
 
         // Extract the image urls
@@ -355,50 +427,68 @@ pub async fn get_img_urls(
         });
     }
 
-    dbg!(&img_urls[0..10]);
-
     Ok(img_urls)
 }
 
+/// The text of a page's SVG (without any markup).
+pub fn page_text(svg: &str) -> String {
+    // The `tspan`s of a line are often split mid-word, but lines are not
+    let text = SVG_TEXT_END_REGEX.replace_all(svg, " ");
+    let text = HTML_TAG_REGEX.replace_all(&text, "");
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The relative urls of all images (and shades) referenced by a page's SVG.
+pub fn page_img_refs(svg: &str) -> BTreeSet<String> {
+    IMG_REGEX
+        .captures_iter(svg)
+        .chain(SHADE_REGEX.captures_iter(svg))
+        .map(|capture| capture[1].to_string())
+        .collect()
+}
+
 pub async fn fetch_img(
     c: &ApiClient,
+    cache: &mut HttpCache,
     // book_meta: &BookMeta,
     // version: &Version,
     // book: &ParsedBook,
     img_urls: &[Img],
     // svg_path: impl AsRef<std::path::Path>,
     img_path: impl AsRef<std::path::Path>,
-) -> anyhow::Result<()> {
-    let ApiClient(client, _) = &c;
-
+    journal: &std::path::Path,
+) -> anyhow::Result<Vec<ManifestEntry>> {
     // Download the images
     let path = img_path.as_ref().to_path_buf();
-    for img in img_urls {
-        let response = client.get(&img.url).send().await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Got non-success status code: {}",
-                response.status()
+    let files = img_urls
+        .iter()
+        .map(|img| {
+            // The extension is corrected once the actual format is known
+            let mut path = path.clone();
+            path.push(format!(
+                "{img_type}_{page_number}_{img_number}.png",
+                img_type = get_img_name(&img.img_type),
+                page_number = img.page_number,
+                img_number = img.img_number
             ));
-        };
-
-        let bytes = response.bytes().await?;
 
-        let mut path = path.clone();
-        path.push(format!(
-            "{img_type}_{page_number}_{img_number}.png",
-            img_type = get_img_name(&img.img_type),
-            page_number = img.page_number,
-            img_number = img.img_number
-        ));
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(&bytes).await?;
-
-        dbg!(&img.url);
-    }
+            (img.url.clone(), path)
+        })
+        .collect();
 
-    Ok(())
+    cache::fetch_all(c, cache, files, Expected::Image, journal, |_, entry| {
+        output::event("image_downloaded", entry);
+    })
+    .await
 }
 
 const fn get_img_name(img_type: &ImgType) -> &'static str {
@@ -409,37 +499,30 @@ const fn get_img_name(img_type: &ImgType) -> &'static str {
 }
 
 pub async fn dl_thumbnails(
-    ApiClient(client, _): &ApiClient,
+    c: &ApiClient,
+    cache: &mut HttpCache,
     book: &BookComplete,
+    pages: Option<&PageRanges>,
     img_path: impl AsRef<std::path::Path>,
-) -> anyhow::Result<()> {
-    for page_number in 1..book.book_meta.page_sizes.len() {
-        let url = format!(
-            "https://a.digi4school.at/ebook/{book_id}/thumbnails/{page_number}.jpg",
-            book_id = book.parsed_book.id
-        );
-
-        let response = client.get(&url).send().await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Got non-success status code: {}",
-                response.status()
-            ));
-        };
-
-        let bytes = response.bytes().await?;
-
-        let mut path = img_path.as_ref().to_path_buf();
-        path.push(format!("thumb_{page_number}.jpg",));
-
-        dbg!(&path, &url);
-
-        tokio::fs::File::create(path)
-            .await?
-            .write_all(&bytes)
-            .await?;
-    }
-
-    Ok(())
+    journal: &std::path::Path,
+) -> anyhow::Result<Vec<ManifestEntry>> {
+    let files = (1..=book.book_meta.page_sizes.len())
+        .filter(|page_number| select::page_selected(pages, *page_number))
+        .map(|page_number| {
+            let url = format!(
+                "https://a.digi4school.at/ebook/{book_id}/thumbnails/{page_number}.jpg",
+                book_id = book.parsed_book.id
+            );
+
+            let mut path = img_path.as_ref().to_path_buf();
+            path.push(format!("thumb_{page_number}.jpg",));
+
+            (url, path)
+        })
+        .collect();
+
+    cache::fetch_all(c, cache, files, Expected::Image, journal, |_, entry| {
+        output::event("thumbnail_downloaded", entry);
+    })
+    .await
 }
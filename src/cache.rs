@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use futures_util::{stream, StreamExt};
+
+use reqwest::{
+    header::{CONTENT_TYPE, IF_MODIFIED_SINCE, IF_NONE_MATCH},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config, interrupt,
+    manifest::{EntryStatus, ManifestEntry},
+    output::say,
+    record, schema,
+    sniff::{self, Expected, FileType},
+    store, throttle,
+    util::{self, ApiClient},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// The validators of every downloaded URL, for making conditional requests
+pub struct HttpCache {
+    entries: HashMap<String, CachedResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedResponse {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// The blob holding the body of the response
+    sha256: String,
+    size: u64,
+}
+
+impl HttpCache {
+    /// Loads the cache from disk (or starts an empty one).
+    pub fn load() -> anyhow::Result<Self> {
+        let path = config::get().http_cache_path();
+
+        if !path.exists() {
+            return Ok(HttpCache::default());
+        }
+
+        schema::load(path)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        schema::save(config::get().http_cache_path(), self)
+    }
+
+    /// The last response for `url`, if the body of it is still around.
+    fn usable(&self, url: &str) -> Option<CachedResponse> {
+        self.entries
+            .get(url)
+            .filter(|cached| store::blob_path(&cached.sha256).exists())
+            .cloned()
+    }
+
+    fn insert(&mut self, entry: &ManifestEntry) {
+        self.entries.insert(
+            entry.url.clone(),
+            CachedResponse {
+                etag: entry.etag.clone(),
+                last_modified: entry.last_modified.clone(),
+                sha256: entry.sha256.clone(),
+                size: entry.size,
+            },
+        );
+    }
+}
+
+/// Downloads files (url, path), unless they didn't change since the last
+/// download; `config::Config::concurrency` at a time.
+///
+/// Unchanged files are linked from the store instead of being transferred.
+/// Every file is checked to be what's `expected` (see `sniff::check`); images
+/// get the extension of their actual format.
+///
+/// `on_fetched` is called with the index of every file once it's done; the
+/// entries are returned in the order of `files`.
+///
+/// Every fetched file is noted in `journal` (see `manifest::journal_path`), so
+/// an interrupted run can be resumed without fetching those files again. The
+/// journal is removed once all files are fetched.
+///
+/// After the first failure (or Ctrl-C) no further files are requested, but
+/// the running requests are finished (and noted) before returning the error.
+pub async fn fetch_all(
+    client: &ApiClient,
+    cache: &mut HttpCache,
+    files: Vec<(String, PathBuf)>,
+    expected: Expected,
+    journal: &Path,
+    mut on_fetched: impl FnMut(usize, &ManifestEntry),
+) -> anyhow::Result<Vec<ManifestEntry>> {
+    let _downloading = interrupt::Downloading::start();
+    // Cached responses are linked from their blobs, which gc mustn't remove meanwhile
+    let _store_lock = store::StoreLock::shared().await?;
+
+    let dirs = files
+        .iter()
+        .filter_map(|(_, path)| path.parent())
+        .collect::<HashSet<_>>();
+
+    for dir in dirs {
+        util::remove_partial_files(dir)?;
+    }
+
+    let mut fetched = read_journal(journal);
+
+    if !fetched.is_empty() {
+        say!(
+            "Resuming an interrupted download ({count} files already fetched).",
+            count = fetched.len()
+        );
+    }
+
+    let failed = AtomicBool::new(false);
+    let failed = &failed;
+
+    let requests = files
+        .into_iter()
+        .map(|(url, path)| {
+            let cached = cache.usable(&url);
+            let resumed = fetched.remove(&url);
+
+            async move {
+                if failed.load(Ordering::SeqCst) {
+                    return Err(anyhow::anyhow!("Skipped after an earlier failure"));
+                }
+                interrupt::check()?;
+
+                match resumed {
+                    Some(entry) => Ok(entry),
+                    None => fetch_uncached(client, cached, &url, &path, expected).await,
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut journal_file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(journal)?;
+
+    let mut responses = stream::iter(requests)
+        .buffered(config::get().concurrency)
+        .enumerate();
+    let mut entries = Vec::new();
+    let mut error = None;
+
+    while let Some((i, entry)) = responses.next().await {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                failed.store(true, Ordering::SeqCst);
+                error.get_or_insert(e);
+                continue;
+            }
+        };
+
+        writeln!(journal_file, "{}", serde_json::to_string(&entry)?)?;
+
+        cache.insert(&entry);
+        on_fetched(i, &entry);
+        entries.push(entry);
+    }
+
+    drop(journal_file);
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    std::fs::remove_file(journal)?;
+
+    Ok(entries)
+}
+
+/// The files noted in a journal which are still intact (by url).
+fn read_journal(journal: &Path) -> HashMap<String, ManifestEntry> {
+    let Ok(contents) = std::fs::read_to_string(journal) else {
+        return HashMap::new();
+    };
+
+    // The last line may be cut off by a crash
+    contents
+        .lines()
+        .filter_map(|line| serde_json::from_str::<ManifestEntry>(line).ok())
+        .filter(|entry| matches!(entry.check(), Ok(EntryStatus::Ok)))
+        .map(|entry| (entry.url.clone(), entry))
+        .collect()
+}
+
+async fn fetch_uncached(
+    ApiClient(client, _): &ApiClient,
+    cached: Option<CachedResponse>,
+    url: &str,
+    path: &Path,
+    expected: Expected,
+) -> anyhow::Result<ManifestEntry> {
+    throttle::wait_for_window().await?;
+
+    let mut request = client.get(url);
+
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = record::send(request).await?;
+
+    if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+        let path = match expected {
+            Expected::Image => {
+                let blob = tokio::fs::read(store::blob_path(&cached.sha256)).await?;
+                sniff::typed_path(path, FileType::detect(&blob, None), expected)
+            }
+            Expected::Page => path.to_path_buf(),
+        };
+
+        store::link(&path, &cached.sha256).await?;
+
+        return Ok(ManifestEntry {
+            path,
+            size: cached.size,
+            sha256: cached.sha256,
+            url: url.to_string(),
+            etag: cached.etag,
+            last_modified: cached.last_modified,
+        });
+    }
+
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Got non-success status code: {}",
+            response.status()
+        ));
+    };
+
+    let headers = response.headers().clone();
+    let bytes = throttle::read_body(response).await?;
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let file_type = sniff::check(url, &bytes, content_type, expected)?;
+    let path = sniff::typed_path(path, file_type, expected);
+
+    store::save(&path, &bytes).await?;
+
+    Ok(ManifestEntry::new(url, path, &headers, &bytes))
+}
@@ -0,0 +1,263 @@
+use crate::{books::BookMeta, crawl::ParsedBook, export::escape_xml};
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CatalogFormat {
+    /// Calibre-compatible `metadata.opf` sidecar files (one per book)
+    Opf,
+    /// One CSV file for the whole library
+    Csv,
+    /// One BibTeX file for the whole library
+    Bibtex,
+}
+
+/// The metadata of a book, as far as it is known
+///
+/// Books which weren't downloaded yet only have the data from the shelf.
+pub struct CatalogEntry<'a> {
+    pub parsed_book: &'a ParsedBook,
+    pub book_meta: Option<&'a BookMeta>,
+}
+
+impl CatalogEntry<'_> {
+    fn title(&self) -> &str {
+        self.book_meta
+            .map_or(&self.parsed_book.title, |meta| &meta.title)
+    }
+
+    fn publisher(&self) -> &str {
+        self.book_meta
+            .map_or(&self.parsed_book.publisher, |meta| &meta.publisher)
+    }
+
+    fn meta_field(&self, field: impl Fn(&BookMeta) -> &String) -> &str {
+        self.book_meta.map_or("", |meta| field(meta).as_str())
+    }
+
+    fn page_count(&self) -> Option<usize> {
+        self.book_meta.map(|meta| meta.page_sizes.len())
+    }
+
+    fn expiry(&self) -> String {
+        self.parsed_book
+            .expiry()
+            .map(|expiry| expiry.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Builds a Calibre `metadata.opf` sidecar file.
+///
+/// `cover` is the file name of the cover next to the sidecar (if any).
+pub fn opf_sidecar(entry: &CatalogEntry, cover: Option<&str>) -> String {
+    let mut meta = Vec::new();
+
+    let sb_number = entry.meta_field(|meta| &meta.sb_number);
+    if !sb_number.is_empty() {
+        meta.push(format!(
+            r#"<dc:identifier opf:scheme="SBNR">{}</dc:identifier>"#,
+            escape_xml(sb_number)
+        ));
+    }
+
+    // Calibre has no standard fields for these, so they go into the description
+    let mut description = [
+        entry.meta_field(|meta| &meta.publisher_address),
+        entry.meta_field(|meta| &meta.publisher_web),
+        entry.meta_field(|meta| &meta.publisher_mail),
+    ]
+    .into_iter()
+    .filter(|field| !field.is_empty())
+    .map(|field| field.to_string())
+    .collect::<Vec<_>>();
+
+    if let Some(page_count) = entry.page_count() {
+        description.push(format!("Pages: {page_count}"));
+    }
+
+    let expiry = entry.expiry();
+    if !expiry.is_empty() {
+        description.push(format!("Licence expires: {expiry}"));
+    }
+
+    if !description.is_empty() {
+        meta.push(format!(
+            "<dc:description>{}</dc:description>",
+            escape_xml(&description.join("\n"))
+        ));
+    }
+
+    let guide = cover
+        .map(|cover| {
+            format!(
+                r#"<guide>
+<reference type="cover" title="Cover" href="{}"/>
+</guide>
+"#,
+                escape_xml(cover)
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+<dc:identifier id="uuid_id" opf:scheme="digi4school">{id}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:publisher>{publisher}</dc:publisher>
+<dc:language>de</dc:language>
+{meta}
+</metadata>
+{guide}</package>
+"#,
+        id = escape_xml(&entry.parsed_book.id),
+        title = escape_xml(entry.title()),
+        publisher = escape_xml(entry.publisher()),
+        meta = meta.join("\n"),
+    )
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Builds a CSV file of the whole library (with a header row).
+pub fn csv(entries: &[CatalogEntry]) -> String {
+    let mut csv = String::from(
+        "id,title,publisher,publisher_address,publisher_web,publisher_mail,sb_number,pages,expiry\n",
+    );
+
+    for entry in entries {
+        let row = [
+            entry.parsed_book.id.clone(),
+            entry.title().to_string(),
+            entry.publisher().to_string(),
+            entry.meta_field(|meta| &meta.publisher_address).to_string(),
+            entry.meta_field(|meta| &meta.publisher_web).to_string(),
+            entry.meta_field(|meta| &meta.publisher_mail).to_string(),
+            entry.meta_field(|meta| &meta.sb_number).to_string(),
+            entry
+                .page_count()
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+            entry.expiry(),
+        ];
+
+        let row = row.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Escapes the characters LaTeX treats specially in a (text) field.
+fn bibtex_field(field: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in field.chars() {
+        match c {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '\n' => escaped.push_str(", "),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// URLs are taken verbatim (by the url package), so only the braces
+/// delimiting the field need to stay balanced.
+fn bibtex_url(url: &str) -> String {
+    url.trim()
+        .replace('{', "%7B")
+        .replace('}', "%7D")
+        .replace(char::is_whitespace, "%20")
+}
+
+/// Builds a BibTeX `@book` entry (keyed `d5s<id>`).
+pub fn bibtex_entry(entry: &CatalogEntry) -> String {
+    let mut fields = vec![
+        ("title", entry.title().to_string()),
+        ("publisher", entry.publisher().to_string()),
+    ];
+
+    let address = entry.meta_field(|meta| &meta.publisher_address);
+    if !address.is_empty() {
+        fields.push(("address", address.to_string()));
+    }
+
+    let web = entry.meta_field(|meta| &meta.publisher_web);
+    if !web.is_empty() {
+        fields.push(("url", web.to_string()));
+    }
+
+    let sb_number = entry.meta_field(|meta| &meta.sb_number);
+    if !sb_number.is_empty() {
+        fields.push(("note", format!("SB-Nr. {sb_number}")));
+    }
+
+    if let Some(page_count) = entry.page_count() {
+        fields.push(("pagetotal", page_count.to_string()));
+    }
+
+    let fields = fields
+        .iter()
+        .map(|(name, value)| {
+            let value = match *name {
+                "url" => bibtex_url(value),
+                _ => bibtex_field(value),
+            };
+
+            format!("  {name} = {{{value}}}")
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+
+    let key = entry
+        .parsed_book
+        .id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>();
+
+    format!("@book{{d5s{key},\n{fields}\n}}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_bibtex_fields() {
+        assert_eq!(
+            bibtex_field(r"50% & more_{x} ~ a^2 \ b"),
+            r"50\% \& more\_\{x\} \textasciitilde{} a\textasciicircum{}2 \textbackslash{} b"
+        );
+        assert_eq!(bibtex_field("Straße 1\nWien"), "Straße 1, Wien");
+    }
+
+    #[test]
+    fn keeps_urls_verbatim() {
+        assert_eq!(
+            bibtex_url(" https://www.example.at/~verlag/a_b?x=1&y=%20 "),
+            "https://www.example.at/~verlag/a_b?x=1&y=%20"
+        );
+        assert_eq!(
+            bibtex_url("https://example.at/{x} y"),
+            "https://example.at/%7Bx%7D%20y"
+        );
+    }
+}
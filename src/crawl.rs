@@ -1,8 +1,18 @@
-use std::fmt::Display;
+use std::{fmt::Display, path::PathBuf};
 
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
 
-use crate::util::ApiClient;
+use crate::{
+    login::BASE_URL,
+    record,
+    sniff::{self, Expected},
+    store,
+    util::ApiClient,
+};
 
 // const R_0: &str = r#""#;
 
@@ -21,6 +31,12 @@ use crate::util::ApiClient;
 ///
 const BOOK_REGEX: &str = r#"<a href='([^']+)'[^>]+?data-code='([^']+)' data-id='([^']+)' class='(bag|all)'[^>]*>.+?src='([^']+)'.+?<h1>([^']+)</h1>.+?<span class='publisher'>([^<]+)</span>.+?<h4>([^<]+)</h4>.+?</a>"#;
 
+lazy_static! {
+    /// A date like `31.07.2024` in the (padded) expiry date of an ebook
+    static ref EXPIRY_DATE_REGEX: Regex =
+        Regex::new(r#"(\d{1,2})\.(\d{1,2})\.(\d{4})"#).unwrap();
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParsedBook {
     pub url: String,
@@ -31,6 +47,35 @@ pub struct ParsedBook {
     pub title: String,
     pub publisher: String,
     pub expiry_date: String,
+    /// The parsed `expiry_date` (if it contains a date)
+    #[serde(default)]
+    pub expires_on: Option<NaiveDate>,
+}
+
+impl ParsedBook {
+    /// The date the licence of the book expires on.
+    ///
+    /// Falls back to parsing `expiry_date` for books crawled before
+    /// `expires_on` existed.
+    pub fn expiry(&self) -> Option<NaiveDate> {
+        self.expires_on
+            .or_else(|| parse_expiry_date(&self.expiry_date))
+    }
+
+    /// The number of days until the licence expires (negative if expired).
+    pub fn days_until_expiry(&self, today: NaiveDate) -> Option<i64> {
+        self.expiry().map(|expiry| (expiry - today).num_days())
+    }
+}
+
+pub fn parse_expiry_date(expiry_date: &str) -> Option<NaiveDate> {
+    let capture = EXPIRY_DATE_REGEX.captures(expiry_date)?;
+
+    NaiveDate::from_ymd_opt(
+        capture[3].parse().ok()?,
+        capture[2].parse().ok()?,
+        capture[1].parse().ok()?,
+    )
 }
 
 impl Display for ParsedBook {
@@ -45,11 +90,51 @@ impl Display for ParsedBook {
     }
 }
 
+/// Downloads the cover of a book to `path` (with the extension of its actual format).
+///
+/// Returns the path it was saved to.
+pub async fn fetch_cover(
+    ApiClient(client, _): &ApiClient,
+    book: &ParsedBook,
+    path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<PathBuf> {
+    // The cover urls on the shelf may be relative
+    let url = reqwest::Url::parse(BASE_URL)?.join(&book.cover_url)?;
+
+    let response = record::send(client.get(url.clone())).await?;
+
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Got non-success status code: {}",
+            response.status()
+        ));
+    };
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let bytes = response.bytes().await?;
+
+    let file_type = sniff::check(
+        url.as_str(),
+        &bytes,
+        content_type.as_deref(),
+        Expected::Image,
+    )?;
+    let path = sniff::typed_path(path.as_ref(), file_type, Expected::Image);
+
+    store::save(&path, &bytes).await?;
+
+    Ok(path)
+}
+
 pub async fn get_books(ApiClient(client, _): &ApiClient) -> anyhow::Result<Vec<ParsedBook>> {
     // HACK move this regex to a static variable
     let regex = regex::Regex::new(BOOK_REGEX).unwrap();
 
-    let response = client.get("https://digi4school.at/ebooks").send().await?;
+    let response = record::send(client.get("https://digi4school.at/ebooks")).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -60,14 +145,6 @@ pub async fn get_books(ApiClient(client, _): &ApiClient) -> anyhow::Result<Vec<P
 
     let text = response.text().await?;
 
-    println!();
-    println!();
-    println!();
-    println!("{text}");
-    println!();
-    println!();
-    println!();
-
     let mut books = Vec::new();
 
     for capture in regex.captures_iter(&text) {
@@ -80,6 +157,7 @@ pub async fn get_books(ApiClient(client, _): &ApiClient) -> anyhow::Result<Vec<P
             title: capture[6].to_string(),
             publisher: capture[7].to_string(),
             expiry_date: capture[8].to_string(),
+            expires_on: parse_expiry_date(&capture[8]),
         };
 
         books.push(book);
@@ -87,3 +165,49 @@ pub async fn get_books(ApiClient(client, _): &ApiClient) -> anyhow::Result<Vec<P
 
     Ok(books)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_padded_expiry_dates() {
+        assert_eq!(
+            parse_expiry_date("  Lizenz gültig bis 31.07.2025  "),
+            NaiveDate::from_ymd_opt(2025, 7, 31)
+        );
+        assert_eq!(
+            parse_expiry_date("bis 1.8.2024"),
+            NaiveDate::from_ymd_opt(2024, 8, 1)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_impossible_dates() {
+        assert_eq!(parse_expiry_date(""), None);
+        assert_eq!(parse_expiry_date("unbegrenzt"), None);
+        assert_eq!(parse_expiry_date("31.02.2025"), None);
+        assert_eq!(parse_expiry_date("31.07.25"), None);
+    }
+
+    #[test]
+    fn prefers_the_stored_expiry() {
+        let mut book = ParsedBook {
+            url: "/ebook/1234".to_string(),
+            code: String::new(),
+            id: "1234".to_string(),
+            visibility: String::new(),
+            cover_url: String::new(),
+            title: "Mathematik 1".to_string(),
+            publisher: String::new(),
+            expiry_date: "bis 31.07.2025".to_string(),
+            expires_on: None,
+        };
+        let today = NaiveDate::from_ymd_opt(2025, 7, 1).unwrap();
+
+        assert_eq!(book.days_until_expiry(today), Some(30));
+
+        book.expires_on = NaiveDate::from_ymd_opt(2025, 6, 30);
+        assert_eq!(book.days_until_expiry(today), Some(-1));
+    }
+}
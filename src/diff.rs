@@ -0,0 +1,213 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    books, config,
+    manifest::{self, sha256_hex, Manifest, ManifestKind},
+    schema, BookComplete,
+};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum PageChange {
+    Added,
+    Removed,
+    /// The SVG of the page wasn't downloaded by one of the runs (or both)
+    Missing {
+        old: bool,
+        new: bool,
+    },
+    /// The page exists in both runs, but differs
+    Changed {
+        size: bool,
+        svg: bool,
+        imgs_added: Vec<String>,
+        imgs_removed: Vec<String>,
+        /// The saved images of the page (by file stem) whose content differs,
+        /// or which only one run has; empty unless both runs got images
+        imgs_changed: Vec<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct PageDiff {
+    pub page: usize,
+    pub change: PageChange,
+}
+
+/// A page of a download run, as far as it is relevant for diffing
+struct RunPage {
+    sha256: String,
+    imgs: BTreeSet<String>,
+}
+
+fn svg_path(book: &BookComplete) -> PathBuf {
+    config::get().svg_run(&book.parsed_book.id, &book.timestamp)
+}
+
+/// Reads a page of a download run, if it was downloaded.
+fn read_page(svg_path: &Path, page: usize) -> anyhow::Result<Option<RunPage>> {
+    let text = match std::fs::read_to_string(svg_path.join(format!("{page}.svg"))) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(RunPage {
+        sha256: sha256_hex(text.as_bytes()),
+        imgs: books::page_img_refs(&text),
+    }))
+}
+
+/// The images of `page` (saved as e.g. `img_3_1.png`) by file stem.
+fn page_images(images: &BTreeMap<String, String>, page: usize) -> BTreeMap<&str, &str> {
+    images
+        .iter()
+        .filter_map(|(name, sha256)| {
+            let stem = name.split('.').next()?;
+            let image_page = stem.split('_').nth(1)?.parse::<usize>().ok()?;
+
+            (image_page == page).then_some((stem, sha256.as_str()))
+        })
+        .collect()
+}
+
+/// The images of `page` which differ between two runs (see `PageChange::Changed`).
+fn changed_images(
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+    page: usize,
+) -> Vec<String> {
+    let old = page_images(old, page);
+    let new = page_images(new, page);
+
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|stem| old.get(*stem) != new.get(*stem))
+        .map(|stem| stem.to_string())
+        .collect()
+}
+
+/// Compares two download runs of the same book page by page.
+///
+/// Only pages which were added, removed, changed or are missing from a run are
+/// returned. The images are compared by content if both runs downloaded them.
+pub fn diff_runs(old: &BookComplete, new: &BookComplete) -> anyhow::Result<Vec<PageDiff>> {
+    if old.parsed_book.id != new.parsed_book.id {
+        return Err(anyhow::anyhow!(
+            "Cannot diff different books ({} and {})",
+            old.parsed_book.id,
+            new.parsed_book.id
+        ));
+    }
+
+    let old_pages = old.book_meta.page_sizes.len();
+    let new_pages = new.book_meta.page_sizes.len();
+    let old_path = svg_path(old);
+    let new_path = svg_path(new);
+    let images = run_images(old)?.zip(run_images(new)?);
+
+    let mut diffs = Vec::new();
+
+    for page in 1..=old_pages.max(new_pages) {
+        if page > new_pages {
+            diffs.push(PageDiff {
+                page,
+                change: PageChange::Removed,
+            });
+            continue;
+        }
+
+        if page > old_pages {
+            diffs.push(PageDiff {
+                page,
+                change: PageChange::Added,
+            });
+            continue;
+        }
+
+        let (old_page, new_page) = match (read_page(&old_path, page)?, read_page(&new_path, page)?)
+        {
+            (Some(old_page), Some(new_page)) => (old_page, new_page),
+            (old_page, new_page) => {
+                diffs.push(PageDiff {
+                    page,
+                    change: PageChange::Missing {
+                        old: old_page.is_none(),
+                        new: new_page.is_none(),
+                    },
+                });
+                continue;
+            }
+        };
+
+        let size = old.book_meta.page_sizes[page - 1] != new.book_meta.page_sizes[page - 1];
+        let svg = old_page.sha256 != new_page.sha256;
+        let imgs_added: Vec<_> = new_page.imgs.difference(&old_page.imgs).cloned().collect();
+        let imgs_removed: Vec<_> = old_page.imgs.difference(&new_page.imgs).cloned().collect();
+        let imgs_changed = images
+            .as_ref()
+            .map(|(old_images, new_images)| changed_images(old_images, new_images, page))
+            .unwrap_or_default();
+
+        if !size
+            && !svg
+            && imgs_added.is_empty()
+            && imgs_removed.is_empty()
+            && imgs_changed.is_empty()
+        {
+            continue;
+        }
+
+        diffs.push(PageDiff {
+            page,
+            change: PageChange::Changed {
+                size,
+                svg,
+                imgs_added,
+                imgs_removed,
+                imgs_changed,
+            },
+        });
+    }
+
+    Ok(diffs)
+}
+
+/// The hashes of the images of a download run by file name, if they were downloaded.
+fn run_images(book: &BookComplete) -> anyhow::Result<Option<BTreeMap<String, String>>> {
+    let path = manifest::manifest_path(&book.parsed_book.id, &book.timestamp, ManifestKind::Images);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let manifest: Manifest = schema::load(path)?;
+
+    let images = manifest
+        .entries
+        .into_iter()
+        .filter_map(|entry| {
+            let name = entry.path.file_name()?.to_string_lossy().into_owned();
+            Some((name, entry.sha256))
+        })
+        .collect();
+
+    Ok(Some(images))
+}
+
+/// Whether two download runs got the same images (by content).
+///
+/// A run without downloaded images never has the same images.
+pub fn same_images(old: &BookComplete, new: &BookComplete) -> anyhow::Result<bool> {
+    match (run_images(old)?, run_images(new)?) {
+        (Some(old_images), Some(new_images)) => Ok(old_images == new_images),
+        _ => Ok(false),
+    }
+}
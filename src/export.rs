@@ -0,0 +1,601 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    books::{BookMeta, TocEntry},
+    output::say,
+    select::{self, PageRanges},
+    sniff, BookComplete,
+};
+
+lazy_static! {
+    /// Links to other pages of the book, as used by the viewer.
+    ///
+    /// 1. The name of the attribute (`href` or `xlink:href`)
+    /// 2. The opening quote (`"` or `'`)
+    /// 3. The target page of a `gotoPage(x)` call
+    /// 4. The target page of a `#page=x`/`?page=x` link
+    /// 5. The closing quote
+    static ref PAGE_LINK_REGEX: Regex = Regex::new(
+        r#"((?:xlink:)?href)=(["'])(?:javascript:\s*)?(?:(?:gotoPage|goToPage|jumpToPage)\(\s*'?(\d+)'?\s*\);?|[?#]page=(\d+))(["'])"#
+    )
+    .unwrap();
+    static ref PAGE_IMG_REGEX: Regex =
+        Regex::new(r#"xlink:href="((img|shade)/(\d+)\.\w+)""#).unwrap();
+    static ref XML_PROLOG_REGEX: Regex = Regex::new(r#"(?s)<\?xml.*?\?>|<!DOCTYPE[^>]*>"#).unwrap();
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// A directory of HTML pages with an index page
+    Html,
+    /// A fixed-layout EPUB 3 file
+    Epub,
+    /// Not a PDF: a Ghostscript pdfmark file with the bookmarks, to add to a
+    /// PDF of the pages (see the file for the command)
+    Pdfmarks,
+}
+
+/// Builds the table of contents of a book.
+///
+/// Uses the navigation of the viewer if there is one, otherwise every page
+/// gets an entry named after its page label.
+pub fn build_toc(book_meta: &BookMeta) -> Vec<TocEntry> {
+    let page_count = book_meta.page_sizes.len();
+
+    let toc = book_meta
+        .toc
+        .iter()
+        .filter(|entry| (1..=page_count).contains(&entry.page))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if !toc.is_empty() {
+        return toc;
+    }
+
+    (1..=page_count)
+        .map(|page| TocEntry {
+            title: page_label(book_meta, page),
+            page,
+        })
+        .collect()
+}
+
+/// The printed label of a (1-based) page, falling back to its number.
+pub fn page_label(book_meta: &BookMeta, page: usize) -> String {
+    book_meta
+        .page_labels
+        .get(page - 1)
+        .cloned()
+        .unwrap_or_else(|| page.to_string())
+}
+
+/// Rewrites the links between pages of a page's SVG to point at `target(page)`.
+///
+/// Links to pages without a target (e.g. left out of the export) lose their
+/// `href`, so they become plain text. Links to page numbers which don't fit in
+/// a `usize` (or with mismatched quotes) are left alone.
+pub fn rewrite_page_links(svg: &str, target: impl Fn(usize) -> Option<String>) -> String {
+    PAGE_LINK_REGEX
+        .replace_all(svg, |capture: &regex::Captures| {
+            let Some(page) = capture
+                .get(3)
+                .or_else(|| capture.get(4))
+                .and_then(|page| page.as_str().parse::<usize>().ok())
+                .filter(|_| capture[2] == capture[5])
+            else {
+                return capture[0].to_string();
+            };
+
+            match target(page) {
+                Some(href) => format!(
+                    "{attr}={quote}{href}{quote}",
+                    attr = &capture[1],
+                    quote = &capture[2]
+                ),
+                None => String::new(),
+            }
+        })
+        .into_owned()
+}
+
+/// Points the images of a page's SVG at the files downloaded by `get-img`.
+///
+/// Returns the rewritten SVG and the files to copy (source, name in `imgs/`),
+/// each only once even if the page uses an image several times.
+pub fn resolve_images(
+    svg: &str,
+    page: usize,
+    img_path: Option<&Path>,
+) -> (String, Vec<(PathBuf, String)>) {
+    let Some(img_path) = img_path else {
+        return (svg.to_string(), Vec::new());
+    };
+
+    let mut files = Vec::new();
+
+    let svg = PAGE_IMG_REGEX
+        .replace_all(svg, |capture: &regex::Captures| {
+            // Same naming scheme as `books::fetch_img`
+            let stem = format!(
+                "{img_type}_{page}_{img_number}",
+                img_type = &capture[2],
+                img_number = &capture[3]
+            );
+
+            match sniff::find_image(img_path, &stem) {
+                Some(name) => {
+                    if !files.iter().any(|(_, file)| *file == name) {
+                        files.push((img_path.join(&name), name.clone()));
+                    }
+                    format!(r#"xlink:href="imgs/{name}""#)
+                }
+                None => capture[0].to_string(),
+            }
+        })
+        .into_owned();
+
+    (svg, files)
+}
+
+/// Strips the XML prolog and doctype, so the SVG can be inlined.
+pub fn inline_svg(svg: &str) -> String {
+    XML_PROLOG_REGEX.replace_all(svg, "").trim().to_string()
+}
+
+fn cover_extension(cover: &Path) -> &str {
+    cover
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("jpg")
+}
+
+pub fn image_media_type(extension: &str) -> &'static str {
+    match extension.to_lowercase().as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "image/jpeg",
+    }
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A page prepared for inlining into an export
+struct ExportPage {
+    page: usize,
+    svg: String,
+    /// The images to copy (source, name in `imgs/`)
+    imgs: Vec<(PathBuf, String)>,
+}
+
+/// Reads the pages of a book, with page links rewritten by `target`.
+///
+/// Links to pages which aren't exported become plain text.
+fn read_pages(
+    book: &BookComplete,
+    svg_path: &Path,
+    img_path: Option<&Path>,
+    page_ranges: Option<&PageRanges>,
+    target: impl Fn(usize) -> String,
+) -> anyhow::Result<Vec<ExportPage>> {
+    let mut exported = Vec::new();
+
+    for page in 1..=book.book_meta.page_sizes.len() {
+        if !select::page_selected(page_ranges, page) {
+            continue;
+        }
+
+        if !svg_path.join(format!("{page}.svg")).exists() {
+            say!("Page {page} is missing; skipping it.");
+            continue;
+        }
+
+        exported.push(page);
+    }
+
+    let mut pages = Vec::new();
+
+    for &page in &exported {
+        let svg = std::fs::read_to_string(svg_path.join(format!("{page}.svg")))?;
+        let svg = rewrite_page_links(&svg, |page| {
+            exported.binary_search(&page).is_ok().then(|| target(page))
+        });
+        let (svg, imgs) = resolve_images(&svg, page, img_path);
+
+        pages.push(ExportPage {
+            page,
+            svg: inline_svg(&svg),
+            imgs,
+        });
+    }
+
+    Ok(pages)
+}
+
+pub fn export_html(
+    book: &BookComplete,
+    svg_path: impl AsRef<Path>,
+    img_path: Option<&Path>,
+    cover: Option<&Path>,
+    page_ranges: Option<&PageRanges>,
+    out_path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let out_path = out_path.as_ref();
+    let meta = &book.book_meta;
+    let title = escape_xml(&meta.title);
+
+    std::fs::create_dir_all(out_path.join("imgs"))?;
+
+    let pages = read_pages(book, svg_path.as_ref(), img_path, page_ranges, |page| {
+        format!("page_{page}.html")
+    })?;
+
+    for (i, ExportPage { page, svg, imgs }) in pages.iter().enumerate() {
+        for (source, name) in imgs {
+            std::fs::copy(source, out_path.join("imgs").join(name))?;
+        }
+
+        let prev = match i.checked_sub(1).map(|i| &pages[i]) {
+            Some(prev) => format!(r#"<a href="page_{}.html">&lt;</a>"#, prev.page),
+            None => String::new(),
+        };
+        let next = match pages.get(i + 1) {
+            Some(next) => format!(r#"<a href="page_{}.html">&gt;</a>"#, next.page),
+            None => String::new(),
+        };
+
+        let html = format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title} – {label}</title>
+</head>
+<body>
+<nav>{prev} <a href="index.html">{title}</a> {next}</nav>
+{svg}
+</body>
+</html>
+"#,
+            label = escape_xml(&page_label(meta, *page)),
+        );
+
+        std::fs::write(out_path.join(format!("page_{page}.html")), html)?;
+    }
+
+    let toc = build_toc(meta)
+        .iter()
+        .filter(|entry| pages.iter().any(|page| page.page == entry.page))
+        .map(|entry| {
+            format!(
+                r#"<li><a href="page_{page}.html">{title}</a></li>"#,
+                page = entry.page,
+                title = escape_xml(&entry.title)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let cover = match cover {
+        Some(cover) => {
+            let name = format!("cover.{}", cover_extension(cover));
+            std::fs::copy(cover, out_path.join("imgs").join(&name))?;
+
+            format!(r#"<img src="imgs/{name}" alt="{title}">"#)
+        }
+        None => String::new(),
+    };
+
+    let index = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+</head>
+<body>
+{cover}
+<h1>{title}</h1>
+<nav>
+<ol>
+{toc}
+</ol>
+</nav>
+</body>
+</html>
+"#
+    );
+
+    std::fs::write(out_path.join("index.html"), index)?;
+
+    Ok(())
+}
+
+pub fn export_epub(
+    book: &BookComplete,
+    svg_path: impl AsRef<Path>,
+    img_path: Option<&Path>,
+    cover: Option<&Path>,
+    page_ranges: Option<&PageRanges>,
+    out_file: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let meta = &book.book_meta;
+    let title = escape_xml(&meta.title);
+
+    let pages = read_pages(book, svg_path.as_ref(), img_path, page_ranges, |page| {
+        format!("page_{page}.xhtml")
+    })?;
+
+    let mut zip = ZipWriter::new(std::fs::File::create(out_file)?);
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The mimetype has to be the first (uncompressed) entry
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles>
+</container>
+"#,
+    )?;
+
+    let mut manifest = Vec::new();
+    let mut spine = Vec::new();
+
+    if let Some(cover) = cover {
+        let extension = cover_extension(cover);
+        let media_type = image_media_type(extension);
+
+        zip.start_file(format!("OEBPS/imgs/cover.{extension}"), stored)?;
+        zip.write_all(&std::fs::read(cover)?)?;
+
+        zip.start_file("OEBPS/cover.xhtml", deflated)?;
+        write!(
+            zip,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<title>{title}</title>
+</head>
+<body epub:type="cover">
+<img src="imgs/cover.{extension}" alt="{title}"/>
+</body>
+</html>
+"#
+        )?;
+
+        manifest.push(format!(
+            r#"<item id="cover-image" href="imgs/cover.{extension}" media-type="{media_type}" properties="cover-image"/>"#
+        ));
+        manifest.push(
+            r#"<item id="cover" href="cover.xhtml" media-type="application/xhtml+xml"/>"#
+                .to_string(),
+        );
+        spine.push(r#"<itemref idref="cover"/>"#.to_string());
+    }
+
+    for ExportPage { page, svg, imgs } in &pages {
+        for (source, name) in imgs {
+            zip.start_file(format!("OEBPS/imgs/{name}"), stored)?;
+            zip.write_all(&std::fs::read(source)?)?;
+
+            let extension = Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("png");
+
+            manifest.push(format!(
+                r#"<item id="{id}" href="imgs/{name}" media-type="{media_type}"/>"#,
+                id = name.replace('.', "_"),
+                media_type = image_media_type(extension)
+            ));
+        }
+
+        let [width, height] = meta.page_sizes[page - 1];
+
+        zip.start_file(format!("OEBPS/page_{page}.xhtml"), deflated)?;
+        write!(
+            zip,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<meta name="viewport" content="width={width}, height={height}"/>
+<title>{title} – {label}</title>
+</head>
+<body>
+{svg}
+</body>
+</html>
+"#,
+            label = escape_xml(&page_label(meta, *page)),
+        )?;
+
+        manifest.push(format!(
+            r#"<item id="page_{page}" href="page_{page}.xhtml" media-type="application/xhtml+xml" properties="svg"/>"#
+        ));
+        spine.push(format!(r#"<itemref idref="page_{page}"/>"#));
+    }
+
+    let toc = build_toc(meta)
+        .iter()
+        .filter(|entry| pages.iter().any(|page| page.page == entry.page))
+        .map(|entry| {
+            format!(
+                r#"<li><a href="page_{page}.xhtml">{title}</a></li>"#,
+                page = entry.page,
+                title = escape_xml(&entry.title)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let page_list = pages
+        .iter()
+        .map(|ExportPage { page, .. }| {
+            format!(
+                r#"<li><a href="page_{page}.xhtml">{label}</a></li>"#,
+                label = escape_xml(&page_label(meta, *page))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head>
+<title>{title}</title>
+</head>
+<body>
+<nav epub:type="toc">
+<h1>{title}</h1>
+<ol>
+{toc}
+</ol>
+</nav>
+<nav epub:type="page-list" hidden="">
+<ol>
+{page_list}
+</ol>
+</nav>
+</body>
+</html>
+"#
+    )?;
+
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="book-id">urn:d5s:{id}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:publisher>{publisher}</dc:publisher>
+<dc:language>de</dc:language>
+<meta property="dcterms:modified">{modified}</meta>
+<meta property="rendition:layout">pre-paginated</meta>
+</metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+{manifest}
+</manifest>
+<spine>
+{spine}
+</spine>
+</package>
+"#,
+        id = escape_xml(&book.parsed_book.id),
+        publisher = escape_xml(&meta.publisher),
+        modified = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        manifest = manifest.join("\n"),
+        spine = spine.join("\n"),
+    )?;
+
+    zip.finish()?;
+
+    Ok(())
+}
+
+/// Writes the table of contents as pdfmarks.
+///
+/// There is no PDF renderer in d5s, so the bookmarks are applied to a PDF of
+/// the (selected) pages using Ghostscript:
+/// `gs -o out.pdf -sDEVICE=pdfwrite book.pdf book.pdfmarks`
+pub fn write_pdfmarks(
+    book: &BookComplete,
+    page_ranges: Option<&PageRanges>,
+    out_file: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out_file)?);
+
+    writeln!(
+        file,
+        "[/Title {title} /Publisher {publisher} /DOCINFO pdfmark",
+        title = pdf_string(&book.book_meta.title),
+        publisher = pdf_string(&book.book_meta.publisher)
+    )?;
+
+    // The PDF only contains the selected pages, so they are renumbered
+    let selected = (1..=book.book_meta.page_sizes.len())
+        .filter(|page| select::page_selected(page_ranges, *page))
+        .collect::<Vec<_>>();
+
+    for entry in build_toc(&book.book_meta) {
+        let Some(position) = selected.iter().position(|page| *page == entry.page) else {
+            continue;
+        };
+
+        writeln!(
+            file,
+            "[/Title {title} /Page {page} /OUT pdfmark",
+            title = pdf_string(&entry.title),
+            page = position + 1
+        )?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Encodes a string as UTF-16BE hex string (with BOM), as PDF text strings
+/// are not UTF-8.
+fn pdf_string(text: &str) -> String {
+    let hex = text
+        .encode_utf16()
+        .map(|unit| format!("{unit:04X}"))
+        .collect::<String>();
+
+    format!("<FEFF{hex}>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(svg: &str) -> String {
+        rewrite_page_links(svg, |page| (page <= 3).then(|| format!("page_{page}.html")))
+    }
+
+    #[test]
+    fn rewrites_quoted_page_links() {
+        assert_eq!(
+            rewrite(r#"<a xlink:href="javascript:gotoPage('2');">"#),
+            r#"<a xlink:href="page_2.html">"#
+        );
+        assert_eq!(rewrite("<a href='#page=3'>"), "<a href='page_3.html'>");
+    }
+
+    #[test]
+    fn unlinks_pages_without_target() {
+        assert_eq!(rewrite(r#"<a href="?page=4">x</a>"#), "<a >x</a>");
+        assert_eq!(
+            rewrite(r##"<a href="#page=2'>"##),
+            r##"<a href="#page=2'>"##
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    OnceLock,
+};
+
+use tokio::sync::Notify;
+
+use crate::output::say;
+
+/// Whether Ctrl-C was pressed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// How many downloads are running (see `Downloading`)
+static DOWNLOADING: AtomicUsize = AtomicUsize::new(0);
+static NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+fn notify() -> &'static Notify {
+    NOTIFY.get_or_init(Notify::new)
+}
+
+/// Handles Ctrl-C from now on.
+///
+/// While files are being downloaded, the first Ctrl-C lets the running
+/// downloads finish (so their progress is kept for resuming) but starts no
+/// new ones. Otherwise, and on a second Ctrl-C, d5s quits right away.
+pub fn listen() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if DOWNLOADING.load(Ordering::SeqCst) == 0 || INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+
+            say!("Interrupted; finishing the running downloads (press Ctrl-C again to quit right away)...");
+            notify().notify_waiters();
+        }
+    });
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fails once Ctrl-C was pressed (to stop before starting more work).
+pub fn check() -> anyhow::Result<()> {
+    if is_interrupted() {
+        Err(anyhow::anyhow!("Interrupted"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Resolves once Ctrl-C is pressed.
+pub async fn interrupted() {
+    loop {
+        let notified = notify().notified();
+
+        if is_interrupted() {
+            return;
+        }
+
+        notified.await;
+    }
+}
+
+/// Marks downloads as running while it is alive.
+pub struct Downloading(());
+
+impl Downloading {
+    pub fn start() -> Self {
+        DOWNLOADING.fetch_add(1, Ordering::SeqCst);
+        Downloading(())
+    }
+}
+
+impl Drop for Downloading {
+    fn drop(&mut self) {
+        DOWNLOADING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
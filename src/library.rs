@@ -0,0 +1,205 @@
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config,
+    crawl::{self, ParsedBook},
+    manifest::{self, ManifestKind},
+    output::say,
+    schema,
+    util::ApiClient,
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// All books which were ever crawled or downloaded
+pub struct Library {
+    pub books: Vec<LibraryBook>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryBook {
+    pub parsed_book: ParsedBook,
+    /// The path to the JSON file containing the full book data of the latest run
+    pub book_data: Option<PathBuf>,
+    /// When the book was last downloaded or found unchanged
+    pub last_synced: Option<String>,
+    /// Whether the book was listed on the shelf when it was last crawled
+    pub listed: bool,
+    /// The downloaded cover image
+    #[serde(default)]
+    pub cover: Option<PathBuf>,
+    /// The url the cover image was downloaded from
+    #[serde(default)]
+    pub cover_url: Option<String>,
+    /// The latest EPUB export
+    #[serde(default)]
+    pub epub: Option<PathBuf>,
+    /// The images of the latest complete image download (not of partial ones)
+    #[serde(default)]
+    pub images: Option<PathBuf>,
+}
+
+impl Library {
+    /// Loads the library from disk (or starts an empty one).
+    pub fn load() -> anyhow::Result<Self> {
+        let path = config::get().library_path();
+
+        if !path.exists() {
+            return Ok(Library::default());
+        }
+
+        schema::load(path)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        schema::save(config::get().library_path(), self)
+    }
+
+    pub fn entry_ref(&self, book_id: &str) -> Option<&LibraryBook> {
+        self.books
+            .iter()
+            .find(|book| book.parsed_book.id == book_id)
+    }
+
+    /// The directory of the latest complete image download of a book.
+    ///
+    /// Falls back to the newest run with an image manifest for books
+    /// downloaded before the library recorded it (thumbnail runs have none).
+    pub fn image_run(&self, book_id: &str) -> Option<PathBuf> {
+        if let Some(images) = self
+            .entry_ref(book_id)
+            .and_then(|book| book.images.clone())
+            .filter(|images| images.exists())
+        {
+            return Some(images);
+        }
+
+        let mut runs = std::fs::read_dir(config::get().imgs_dir().join(book_id))
+            .ok()?
+            .filter_map(|dir| Some(dir.ok()?.path()))
+            .filter(|dir| dir.is_dir())
+            .collect::<Vec<_>>();
+
+        runs.sort();
+
+        runs.into_iter().rev().find(|run| {
+            let timestamp = run.file_name().unwrap_or_default().to_string_lossy();
+            manifest::manifest_path(book_id, &timestamp, ManifestKind::Images).exists()
+        })
+    }
+
+    /// Gets a book, adding it to the library if it is new.
+    pub fn entry(&mut self, parsed_book: &ParsedBook) -> &mut LibraryBook {
+        let index = match self
+            .books
+            .iter()
+            .position(|book| book.parsed_book.id == parsed_book.id)
+        {
+            Some(index) => index,
+            None => {
+                self.books.push(LibraryBook {
+                    parsed_book: parsed_book.clone(),
+                    book_data: None,
+                    last_synced: None,
+                    listed: true,
+                    cover: None,
+                    cover_url: None,
+                    epub: None,
+                    images: None,
+                });
+                self.books.len() - 1
+            }
+        };
+
+        &mut self.books[index]
+    }
+
+    /// Updates the library with a fresh crawl of the shelf.
+    ///
+    /// Returns the books which are no longer listed.
+    pub fn update_listing(&mut self, crawled: &[ParsedBook]) -> Vec<ParsedBook> {
+        for parsed_book in crawled {
+            let book = self.entry(parsed_book);
+            book.parsed_book = parsed_book.clone();
+            book.listed = true;
+        }
+
+        let mut unlisted = Vec::new();
+
+        for book in self.books.iter_mut() {
+            if !crawled.iter().any(|b| b.id == book.parsed_book.id) && book.listed {
+                book.listed = false;
+                unlisted.push(book.parsed_book.clone());
+            }
+        }
+
+        unlisted
+    }
+
+    /// Downloads the covers of all listed books, unless they are up to date.
+    pub async fn update_covers(&mut self, client: &ApiClient) -> anyhow::Result<()> {
+        for book in self.books.iter_mut().filter(|book| book.listed) {
+            let up_to_date = book.cover_url.as_ref() == Some(&book.parsed_book.cover_url)
+                && book.cover.as_ref().is_some_and(|cover| cover.exists());
+
+            if up_to_date || book.parsed_book.cover_url.is_empty() {
+                continue;
+            }
+
+            let extension = Path::new(&book.parsed_book.cover_url)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .filter(|extension| extension.len() <= 4)
+                .unwrap_or("jpg");
+
+            let path = config::get()
+                .covers_dir()
+                .join(format!("{id}.{extension}", id = book.parsed_book.id));
+
+            match crawl::fetch_cover(client, &book.parsed_book, &path).await {
+                Ok(path) => {
+                    book.cover = Some(path);
+                    book.cover_url = Some(book.parsed_book.cover_url.clone());
+                }
+                Err(e) => say!(
+                    "Failed to download the cover of {title}: {e:#}",
+                    title = book.parsed_book.title
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The listed books, sorted by expiry date (books without one come last).
+    pub fn by_expiry(&self) -> Vec<&LibraryBook> {
+        let mut books = self
+            .books
+            .iter()
+            .filter(|book| book.listed)
+            .collect::<Vec<_>>();
+
+        books.sort_by_key(|book| {
+            (
+                book.parsed_book.expiry().is_none(),
+                book.parsed_book.expiry(),
+            )
+        });
+
+        books
+    }
+
+    /// The listed books expiring within the next `days` days.
+    pub fn expiring_within(&self, today: NaiveDate, days: i64) -> Vec<&LibraryBook> {
+        self.by_expiry()
+            .into_iter()
+            .filter(|book| {
+                book.parsed_book
+                    .days_until_expiry(today)
+                    .is_some_and(|remaining| remaining <= days)
+            })
+            .collect()
+    }
+}
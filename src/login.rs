@@ -1,9 +1,8 @@
-use std::{path::Path, sync::Arc};
+use std::path::Path;
 
-use reqwest::ClientBuilder;
-use reqwest_cookie_store::CookieStoreMutex;
 use serde::{Deserialize, Serialize};
-use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::{record, schema};
 
 pub const BASE_URL: &str = "https://digi4school.at/";
 pub const LOGIN_URL: &str = "https://digi4school.at/br/xhr/login";
@@ -15,19 +14,11 @@ pub struct Credentials {
 }
 
 pub async fn get_credentials(path: impl AsRef<Path>) -> anyhow::Result<Credentials> {
-    let mut file = File::open(path).await?;
-    let mut text = String::new();
-    file.read_to_string(&mut text).await?;
-
-    let credentials = serde_json::from_str(&text)?;
-
-    Ok(credentials)
+    schema::load(path)
 }
 
-pub async fn do_init_get(cookie_store: Arc<CookieStoreMutex>) -> anyhow::Result<()> {
-    let client = ClientBuilder::new().cookie_provider(cookie_store).build()?;
-
-    let response = client.get(BASE_URL).send().await?;
+pub async fn do_init_get(client: &reqwest::Client) -> anyhow::Result<()> {
+    let response = record::send(client.get(BASE_URL)).await?;
 
     if !response.status().is_success() {
         return Err(anyhow::anyhow!(
@@ -48,7 +39,10 @@ pub async fn perform_login(
     form.insert("password", &credentials.password);
     form.insert("indefinite", "1");
 
-    let response = client.post(LOGIN_URL).form(&form).send().await?;
+    record::redact(&credentials.email);
+    record::redact(&credentials.password);
+
+    let response = record::send(client.post(LOGIN_URL).form(&form)).await?;
 
     if response.status().is_success() {
         Ok(())
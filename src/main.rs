@@ -1,89 +1,315 @@
 // #![allow(dead_code, unused_variables)]
 
 use std::{
-    cmp::min,
-    io::Write,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
 use anyhow::Context;
 use books::BookMeta;
+use cache::HttpCache;
+use catalog::{CatalogEntry, CatalogFormat};
 use clap::{Parser, Subcommand};
+use config::{Config, ConfigOverrides};
 use crawl::ParsedBook;
+use diff::PageChange;
+use export::ExportFormat;
+use library::Library;
+use manifest::{EntryStatus, Manifest, ManifestKind};
+use output::{say, OutputFormat};
+use queue::{EditLock, Job, JobAction, JobStatus, Queue, WorkerLock};
 use reqwest_cookie_store::CookieStoreMutex;
+use select::{BookSelection, PageRanges};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use template::TemplateVars;
 use util::{make_dirs, ApiClient};
 
 use crate::login::BASE_URL;
 
 mod books;
+mod cache;
+mod catalog;
 mod cli;
+mod config;
 mod crawl;
+mod diff;
+mod export;
+mod interrupt;
+mod library;
 mod login;
+mod manifest;
+mod output;
+mod queue;
+mod record;
+mod schema;
+mod select;
+mod serve;
+mod sniff;
+mod store;
+mod template;
+mod throttle;
 mod util;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let timestamp = chrono::Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
 
-    make_dirs();
-
     let cli = Cli::parse();
 
-    match cli.command {
+    output::set_format(cli.output);
+
+    if let Some(record) = &cli.record {
+        record::start(record);
+    }
+
+    let replay = cli.replay.as_ref().map_or(Ok(()), record::replay);
+
+    let result = match replay.and_then(|()| Config::load(&cli.config)) {
+        Ok(config) => {
+            // Older versions always used ./d5s, so point out where the data went
+            let legacy = Path::new("d5s");
+            if legacy.is_dir() && legacy.canonicalize().ok() != config.data_dir.canonicalize().ok()
+            {
+                say!(
+                    "Note: using the data directory {data_dir}, not ./d5s (use --data-dir d5s to keep using that).",
+                    data_dir = config.data_dir.display()
+                );
+            }
+
+            config::init(config);
+
+            match make_dirs() {
+                Ok(()) => {
+                    interrupt::listen();
+                    record::redact_stored_credentials();
+
+                    run(&timestamp, cli.command).await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    // Especially failed runs are worth a look
+    let result = result.and(record::finish());
+
+    if let Err(e) = result {
+        output::error(&e);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run(timestamp: &str, command: Commands) -> anyhow::Result<()> {
+    match command {
         Commands::Login { path } => {
-            handle_login(&timestamp, &path).await.unwrap();
+            output::result(handle_login(timestamp, &path).await?);
         }
         // Commands::Resume { login_cookies } => {
-        //     handle_resume(&timestamp, &login_cookies).await.unwrap();
+        //     handle_resume(&login_cookies).await.unwrap();
         // }
         Commands::CrawlBooks { login_cookies } => {
-            handle_crawl_books(&timestamp, &login_cookies)
-                .await
-                .unwrap();
+            output::result(handle_crawl_books(timestamp, &login_cookies).await?);
         }
         Commands::CrawlInfo { book_metadata } => {
-            handle_crawl_info(&book_metadata).await.unwrap();
+            output::result(handle_crawl_info(&book_metadata).await?);
         }
         Commands::GetBook {
             login_cookies,
             book_metadata,
             index,
+            selection,
+            pages,
         } => {
-            handle_get_book(&timestamp, &login_cookies, &book_metadata, index)
-                .await
-                .unwrap();
+            output::result(
+                handle_get_book(
+                    timestamp,
+                    &login_cookies,
+                    &book_metadata,
+                    index,
+                    &selection,
+                    pages.as_ref(),
+                )
+                .await?,
+            );
         }
         Commands::GetImg {
             login_cookies,
             full_book_data,
+            selection,
+            pages,
         } => {
-            handle_get_img(&timestamp, &login_cookies, &full_book_data)
-                .await
-                .unwrap();
+            let mut results = Vec::new();
+
+            for full_book_data in select_book_data(full_book_data, &selection)? {
+                results.push(
+                    handle_get_img(timestamp, &login_cookies, &full_book_data, pages.as_ref())
+                        .await?,
+                );
+            }
+
+            output::result(results);
         }
         Commands::GetThumbs {
             login_cookies,
             full_book_data,
+            selection,
+            pages,
         } => {
-            handle_get_thumbs(&timestamp, &login_cookies, &full_book_data)
-                .await
-                .unwrap();
+            let mut results = Vec::new();
+
+            for full_book_data in select_book_data(full_book_data, &selection)? {
+                results.push(
+                    handle_get_thumbs(timestamp, &login_cookies, &full_book_data, pages.as_ref())
+                        .await?,
+                );
+            }
+
+            output::result(results);
+        }
+        Commands::Export {
+            full_book_data,
+            format,
+            img_dir,
+            selection,
+            pages,
+        } => {
+            let mut results = Vec::new();
+
+            for full_book_data in select_book_data(full_book_data, &selection)? {
+                results.push(
+                    handle_export(
+                        timestamp,
+                        &full_book_data,
+                        format.unwrap_or(config::get().export_format),
+                        img_dir.as_deref(),
+                        pages.as_ref(),
+                    )
+                    .await?,
+                );
+            }
+
+            output::result(results);
+        }
+        Commands::Catalog { format, selection } => {
+            let format = format.unwrap_or(config::get().catalog_format);
+            output::result(handle_catalog(timestamp, format, &selection).await?);
+        }
+        Commands::Serve { address } => {
+            serve::serve(address).await?;
         }
-        Commands::Auto { redo_login } => {
-            handle_auto(&timestamp, redo_login).await.unwrap();
+        Commands::Verify {
+            full_book_data,
+            refetch,
+            login_cookies,
+        } => {
+            output::result(
+                handle_verify(&full_book_data, refetch, login_cookies.as_deref()).await?,
+            );
+        }
+        Commands::Diff {
+            old_book_data,
+            new_book_data,
+        } => {
+            output::result(handle_diff(&old_book_data, &new_book_data).await?);
+        }
+        Commands::Gc => {
+            output::result(handle_gc().await?);
+        }
+        Commands::Config { init } => {
+            output::result(handle_config(init)?);
+        }
+        Commands::Migrate { dry_run } => {
+            output::result(handle_migrate(dry_run)?);
+        }
+        Commands::Sync {
+            redo_login,
+            skip_images,
+            expiry_window,
+        } => {
+            output::result(handle_sync(timestamp, redo_login, skip_images, expiry_window).await?);
+        }
+        Commands::Expiry => {
+            output::result(handle_expiry().await?);
+        }
+        Commands::Queue { command } => match command {
+            QueueCommand::Add {
+                action,
+                selection,
+                format,
+                skip_images,
+            } => {
+                output::result(handle_queue_add(
+                    timestamp,
+                    action,
+                    &selection,
+                    format,
+                    skip_images,
+                )?);
+            }
+            QueueCommand::List => {
+                output::result(handle_queue_list()?);
+            }
+            QueueCommand::Cancel { ids } => {
+                output::result(handle_queue_cancel(&ids)?);
+            }
+            QueueCommand::Retry { ids, failed } => {
+                output::result(handle_queue_retry(&ids, failed)?);
+            }
+            QueueCommand::Clear => {
+                output::result(handle_queue_clear()?);
+            }
+            QueueCommand::Run { redo_login } => {
+                output::result(handle_queue_run(timestamp, redo_login).await?);
+            }
+        },
+        Commands::Auto {
+            redo_login,
+            expiry_window,
+            download_expiring,
+            selection,
+        } => {
+            output::result(
+                handle_auto(
+                    timestamp,
+                    redo_login,
+                    expiry_window,
+                    download_expiring,
+                    &selection,
+                )
+                .await?,
+            );
         }
     };
 
     Ok(())
 }
 
+/// Download the e-books of a digi4school account (pages, images and metadata),
+/// keep them in sync and export them.
 #[derive(Parser)]
 #[command()]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// How to print results (JSON/NDJSON go to stdout, messages to stderr).
+    #[clap(short, long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Record all HTTP traffic to this HAR file (credentials are redacted), e.g. for bug reports.
+    #[clap(long, global = true, env = "D5S_RECORD")]
+    record: Option<PathBuf>,
+
+    /// Answer all HTTP requests from this HAR file (e.g. from --record) instead of the network.
+    #[clap(long, global = true, env = "D5S_REPLAY", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    #[clap(flatten)]
+    config: ConfigOverrides,
 }
 
 #[derive(Subcommand)]
@@ -102,7 +328,7 @@ enum Commands {
         // }
         //
         // The path to the JSON file containing the credentials ("email" and "password").
-        // In case of using the automatic mode to generate the login, the path should be "d5s/keys/credentials/auto_creds.json".
+        // In case of using the automatic mode to generate the login, the path should be "<data dir>/keys/credentials/auto_creds.json".
         path: String,
     },
     // Resume {
@@ -113,65 +339,286 @@ enum Commands {
     CrawlBooks {
         // #[clap(short, long)]
         /// The path to the JSON file containing the cookies after a successful login.
-        /// (default d5s/keys/cookies/2023..._login.json)
+        /// (default <data dir>/keys/cookies/2023..._login.json)
         login_cookies: String,
     },
     CrawlInfo {
         // #[clap(short, long)]
         /// The path to the JSON file containing the book metadata.
-        /// (default d5s/downloads/meta/2023..._books.json)
+        /// (default <data dir>/downloads/meta/2023..._books.json)
         book_metadata: String,
     },
+    /// Download the pages (without images) of a book of a crawled shelf.
     GetBook {
         // #[clap(short, long)]
         /// The path to the JSON file containing the cookies after a successful login.
-        /// (default d5s/keys/cookies/2023..._login.json)
+        /// (default <data dir>/keys/cookies/2023..._login.json)
         login_cookies: String,
 
         // #[clap(short, long)]
         /// The path to the JSON file containing the book metadata.
-        /// (default d5s/downloads/meta/2023..._books.json)
+        /// (default <data dir>/downloads/meta/2023..._books.json)
         book_metadata: String,
 
         // #[clap(short, long)]
         /// The index of the book to download (starting at 0).
-        index: usize,
+        /// Can be omitted when using the selection flags.
+        #[clap(conflicts_with_all = [
+            "id",
+            "title_regex",
+            "publisher",
+            "visibility",
+            "all",
+            "expiring_within",
+        ])]
+        index: Option<usize>,
+
+        #[clap(flatten)]
+        selection: BookSelection,
+
+        /// Only these pages, e.g. 1-20,45,100- (default: all pages).
+        #[clap(short, long)]
+        pages: Option<PageRanges>,
     },
+    /// Download the images of the pages of a downloaded book.
     GetImg {
         // #[clap(short, long)]
         /// The path to the JSON file containing the cookies after a successful login.
-        /// (default d5s/keys/cookies/2023..._login.json)
+        /// (default <data dir>/keys/cookies/2023..._login.json)
         login_cookies: String,
 
         // #[clap(short, long)]
         /// The path to the JSON file containing the book metadata.
-        /// (default d5s/downloads/meta/2023..._books.json)
-        full_book_data: String,
+        /// (default <data dir>/downloads/meta/2023..._books.json)
+        /// Can be omitted when using the selection flags.
+        full_book_data: Option<String>,
+
+        #[clap(flatten)]
+        selection: BookSelection,
+
+        /// Only these pages, e.g. 1-20,45,100- (default: all pages).
+        #[clap(short, long)]
+        pages: Option<PageRanges>,
     },
+    /// Download the page thumbnails of a downloaded book.
     GetThumbs {
         // #[clap(short, long)]
         /// The path to the JSON file containing the cookies after a successful login.
-        /// (default d5s/keys/cookies/2023..._login.json)
+        /// (default <data dir>/keys/cookies/2023..._login.json)
         login_cookies: String,
 
         // #[clap(short, long)]
         /// The path to the JSON file containing the book metadata.
-        /// (default d5s/downloads/meta/2023..._books.json)
+        /// (default <data dir>/downloads/meta/2023..._books.json)
+        /// Can be omitted when using the selection flags.
+        full_book_data: Option<String>,
+
+        #[clap(flatten)]
+        selection: BookSelection,
+
+        /// Only these pages, e.g. 1-20,45,100- (default: all pages).
+        #[clap(short, long)]
+        pages: Option<PageRanges>,
+    },
+    /// Export a downloaded book (with its table of contents).
+    Export {
+        // #[clap(short, long)]
+        /// The path to the JSON file containing the full book data.
+        /// (default <data dir>/downloads/meta/book_data_..._.json)
+        /// Can be omitted when using the selection flags.
+        full_book_data: Option<String>,
+
+        #[clap(flatten)]
+        selection: BookSelection,
+
+        /// The format to export to (default: export_format of the config, or html).
+        /// pdfmarks writes a Ghostscript pdfmark file (bookmarks), not a PDF.
+        #[clap(short, long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// The directory containing the images downloaded by get-img.
+        /// (default: the latest <data dir>/downloads/imgs/<id>/2023...)
+        #[clap(short, long)]
+        img_dir: Option<String>,
+
+        /// Only these pages, e.g. 1-20,45,100- (default: all pages).
+        #[clap(short, long)]
+        pages: Option<PageRanges>,
+    },
+    /// Export the metadata of the library (e.g. for Calibre or a reference manager).
+    Catalog {
+        /// The format to export to (default: catalog_format of the config, or csv).
+        #[clap(short, long, value_enum)]
+        format: Option<CatalogFormat>,
+
+        /// Only export the selected books (default: all books of the library).
+        #[clap(flatten)]
+        selection: BookSelection,
+    },
+    /// Serve the downloaded books as a web UI and an OPDS catalog (e.g. for e-reader apps).
+    ///
+    /// The web UI is at /, the catalog at /opds (offering the latest EPUB exports).
+    Serve {
+        /// The address to listen on (only this machine by default; use e.g.
+        /// 0.0.0.0:8080 to reach it from e-readers on the network).
+        #[clap(short, long, default_value = "127.0.0.1:8080")]
+        address: std::net::SocketAddr,
+    },
+    /// Verify the downloaded files of a book against their manifests.
+    Verify {
+        // #[clap(short, long)]
+        /// The path to the JSON file containing the full book data.
+        /// (default <data dir>/downloads/meta/book_data_..._.json)
         full_book_data: String,
+
+        /// Re-fetch missing or corrupt files.
+        #[clap(short, long, requires = "login_cookies")]
+        refetch: bool,
+
+        /// The path to the JSON file containing the cookies after a successful login.
+        /// (default <data dir>/keys/cookies/2023..._login.json)
+        #[clap(short, long)]
+        login_cookies: Option<String>,
+    },
+    /// Compare two download runs of the same book page by page.
+    Diff {
+        // #[clap(short, long)]
+        /// The path to the JSON file containing the full book data of the older run.
+        /// (default <data dir>/downloads/meta/book_data_..._.json)
+        old_book_data: String,
+
+        // #[clap(short, long)]
+        /// The path to the JSON file containing the full book data of the newer run.
+        /// (default <data dir>/downloads/meta/book_data_..._.json)
+        new_book_data: String,
+    },
+    /// Remove stored files no longer used by any download run.
+    ///
+    /// Refuses to run while files are being downloaded.
+    Gc,
+    /// Show the effective configuration (after applying flags and environment variables).
+    Config {
+        /// Write it to the config file, to edit it from there.
+        #[clap(long)]
+        init: bool,
+    },
+    /// Upgrade the stored metadata (book data, shelves, library, ...) to the current format.
+    ///
+    /// Older files are still read, but only upgraded once written again.
+    /// Use --data-dir d5s to upgrade a tree from before the config file.
+    Migrate {
+        /// Only show what would be upgraded.
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Mirror the whole shelf without any prompts (e.g. from a cron job).
+    ///
+    /// Uses the cookies/credentials stored by the automatic mode.
+    Sync {
+        /// Redo login (even if cookies exist).
+        #[clap(short, long)]
+        redo_login: bool,
+
+        /// Only download the pages, not the images.
+        #[clap(short, long)]
+        skip_images: bool,
+
+        /// Warn about books expiring within this many days.
+        #[clap(short, long, default_value_t = 30)]
+        expiry_window: i64,
+    },
+    /// List the books of the library by licence expiry date.
+    Expiry,
+    /// Manage the queue of books to download, refresh or export.
+    ///
+    /// The queue is kept on disk; a job interrupted by a crash or Ctrl-C
+    /// resumes where it stopped on the next `queue run`.
+    Queue {
+        #[clap(subcommand)]
+        command: QueueCommand,
     },
     /// Automatic, interactive mode (recommended).
     Auto {
         /// Redo login (even if cookies/creds exist).
         #[clap(short, long)]
         redo_login: bool,
+
+        /// Warn about books expiring within this many days.
+        #[clap(short, long, default_value_t = 30)]
+        expiry_window: i64,
+
+        /// Download books expiring within the window which weren't downloaded yet.
+        #[clap(short, long)]
+        download_expiring: bool,
+
+        /// Download the selected books instead of asking which ones to download.
+        #[clap(flatten)]
+        selection: BookSelection,
     },
 }
 
-async fn handle_auto(now_timestamp: &str, redo_login: bool) -> anyhow::Result<()> {
+#[derive(Subcommand)]
+enum QueueCommand {
+    /// Add a job for every selected book of the library.
+    Add {
+        /// What to do with the books.
+        #[clap(value_enum)]
+        action: JobAction,
+
+        #[clap(flatten)]
+        selection: BookSelection,
+
+        /// The format to export to (default: export_format of the config, or html).
+        /// pdfmarks writes a Ghostscript pdfmark file (bookmarks), not a PDF.
+        #[clap(short, long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// Only download the pages, not the images.
+        #[clap(short, long)]
+        skip_images: bool,
+    },
+    /// List the jobs of the queue.
+    List,
+    /// Cancel queued jobs.
+    ///
+    /// Cancelling a running job only takes effect once it finishes: its download
+    /// or export is completed (and kept), but the job isn't marked as done. An
+    /// interrupted job which was cancelled isn't resumed.
+    Cancel {
+        /// The IDs of the jobs.
+        #[clap(required = true)]
+        ids: Vec<u64>,
+    },
+    /// Queue failed or cancelled jobs again.
+    Retry {
+        /// The IDs of the jobs.
+        ids: Vec<u64>,
+
+        /// Retry all failed jobs.
+        #[clap(long, conflicts_with = "ids")]
+        failed: bool,
+    },
+    /// Remove the finished (done or cancelled) jobs.
+    Clear,
+    /// Process the queue until it's empty (uses the login of the automatic mode).
+    Run {
+        /// Redo login (even if cookies exist).
+        #[clap(short, long)]
+        redo_login: bool,
+    },
+}
+
+async fn handle_auto(
+    now_timestamp: &str,
+    redo_login: bool,
+    expiry_window: i64,
+    download_expiring: bool,
+    selection: &BookSelection,
+) -> anyhow::Result<Vec<DownloadedBook>> {
     // Assume all data is located in the default directories
-    let auto_creds = Path::new("d5s/keys/credentials/auto_creds.json");
-    let auto_cookies = Path::new("d5s/keys/cookies/auto_login.json");
-    let auto_book_metadata = Path::new("d5s/downloads/meta/auto_books.json");
+    let auto_creds = &config::get().auto_creds();
+    let auto_cookies = &config::get().auto_cookies();
+    let auto_book_metadata = &config::get().auto_book_metadata();
     let credentials;
     let api_client;
 
@@ -179,7 +626,7 @@ async fn handle_auto(now_timestamp: &str, redo_login: bool) -> anyhow::Result<()
     if !auto_cookies.exists() || redo_login {
         // If not, check if credentials exist
 
-        println!(
+        say!(
             "Cookies missing; checking for credentials in {auto_creds}",
             auto_creds = auto_creds.display()
         );
@@ -187,150 +634,1465 @@ async fn handle_auto(now_timestamp: &str, redo_login: bool) -> anyhow::Result<()
         if !auto_creds.exists() || redo_login {
             // If not, ask for credentials
 
-            println!("Username & password missing; please log in to digi4school:");
+            say!("Username & password missing; please log in to digi4school:");
 
             // Retry cli::get_credentials() in a while loop until it succeeds
             credentials = loop {
                 match cli::get_credentials() {
                     Ok(credentials) => break credentials,
                     Err(e) => {
-                        println!("Error: {e}", e = e);
-                        println!("Please try again (or just press enter twice to exit).");
+                        say!("Error: {e}", e = e);
+                        say!("Please try again (or just press enter twice to exit).");
                         continue;
                     }
                 }
             };
 
             // Save credentials to disk
-            let mut file = std::fs::File::create(auto_creds)?;
-            serde_json::to_writer_pretty(&mut file, &credentials)?;
+            schema::save(auto_creds, &credentials)?;
         } else {
-            print!("Username & password found; logging in...");
+            say!("Username & password found; logging in...");
             // If so, load credentials from disk
-            let json = std::fs::read_to_string(auto_creds);
-            credentials = serde_json::from_str(&json?)?;
+            credentials = schema::load(auto_creds)?;
         }
 
         // Then login and save cookies to disk
-        api_client = util::make_client_and_store();
+        api_client = util::make_client_and_store()?;
         let ApiClient(client, cookie_store) = &api_client;
 
-        login::perform_login(&client, &credentials)
+        login::perform_login(client, &credentials)
             .await
-            .context("Login failed; maybe re-try password entry with --redo-login")
-            .unwrap();
+            .context("Login failed; maybe re-try password entry with --redo-login")?;
 
         // Write the cookies to disk
-        write_cookies_to_disk_detailed(cookie_store.clone(), auto_cookies)
-            .await
-            .unwrap();
+        write_cookies_to_disk_detailed(cookie_store.clone(), auto_cookies).await?;
 
-        println!("Logged in successfully.");
+        say!("Logged in successfully.");
     } else {
-        println!("Using pre-existing login cookies.");
-        println!("If you want to login again, use --redo-login.");
+        say!("Using pre-existing login cookies.");
+        say!("If you want to login again, use --redo-login.");
 
         // If so, load cookies from disk
         api_client = util::load_cookies_from_json(auto_cookies).await?;
     }
 
     // Crawl books
-    let books = crawl::get_books(&api_client).await.unwrap();
-    let mut file = std::fs::File::create(auto_book_metadata)?;
-    serde_json::to_writer_pretty(&mut file, &books)?;
+    let books = crawl::get_books(&api_client).await?;
+    schema::save(auto_book_metadata, &books)?;
+
+    say!("Crawled books successfully.");
+
+    let mut library = Library::load()?;
+    library.update_listing(&books);
+    library.update_covers(&api_client).await?;
+    library.save()?;
+
+    warn_expiring(&library, expiry_window);
+
+    let mut downloaded = Vec::new();
+
+    if download_expiring {
+        let today = chrono::Local::now().date_naive();
+        let expiring = library
+            .expiring_within(today, expiry_window)
+            .into_iter()
+            .filter(|book| book.book_data.is_none())
+            .map(|book| book.parsed_book.clone())
+            .collect::<Vec<_>>();
+
+        let mut cache = HttpCache::load()?;
+
+        for book in &expiring {
+            say!("Downloading expiring book {title}...", title = book.title);
+
+            let (_, book_data) =
+                sync_book(now_timestamp, &api_client, &mut cache, book, None, false).await?;
+            cache.save()?;
+
+            downloaded.push(DownloadedBook::new(book, &book_data));
+
+            let entry = library.entry(book);
+            entry.book_data = Some(book_data);
+            entry.last_synced = Some(now_timestamp.to_string());
+            library.save()?;
+        }
+    }
+
+    // Ask for which book to download (unless selected using flags)
+    let selection = if selection.is_empty() {
+        cli::book_selection(&books)?
+    } else {
+        selection.select(&books)?.into_iter().cloned().collect()
+    };
+
+    let mut cache = HttpCache::load()?;
+
+    for book in &selection {
+        say!("Downloading {title}...", title = book.title);
+
+        let previous = library.entry(book).book_data.clone();
+        let (_, book_data) = sync_book(
+            now_timestamp,
+            &api_client,
+            &mut cache,
+            book,
+            previous.as_deref(),
+            false,
+        )
+        .await?;
+        cache.save()?;
+
+        downloaded.push(DownloadedBook::new(book, &book_data));
+
+        let entry = library.entry(book);
+        entry.book_data = Some(book_data);
+        entry.last_synced = Some(now_timestamp.to_string());
+        library.save()?;
+    }
+
+    Ok(downloaded)
+}
+
+/// Resolves the full book data to use: either the given path or the latest
+/// download of every book in the library matching the selection.
+fn select_book_data(
+    full_book_data: Option<String>,
+    selection: &BookSelection,
+) -> anyhow::Result<Vec<PathBuf>> {
+    if let Some(full_book_data) = full_book_data {
+        return Ok(vec![PathBuf::from(full_book_data)]);
+    }
+
+    let library = Library::load()?;
+    let books = library
+        .books
+        .iter()
+        .map(|book| book.parsed_book.clone())
+        .collect::<Vec<_>>();
+
+    let mut paths = Vec::new();
+
+    for book in selection.select(&books)? {
+        match library
+            .entry_ref(&book.id)
+            .and_then(|b| b.book_data.clone())
+        {
+            Some(book_data) => paths.push(book_data),
+            None => say!(
+                "{title} wasn't downloaded yet; skipping it.",
+                title = book.title
+            ),
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Logs in without any prompts, using the cookies or credentials of the
+/// automatic mode.
+async fn login_non_interactive(redo_login: bool) -> anyhow::Result<ApiClient> {
+    let auto_creds = &config::get().auto_creds();
+    let auto_cookies = &config::get().auto_cookies();
+
+    if auto_cookies.exists() && !redo_login {
+        say!("Using pre-existing login cookies.");
+        return util::load_cookies_from_json(auto_cookies).await;
+    }
+
+    let credentials = login::get_credentials(auto_creds).await.context(format!(
+        "No credentials in {auto_creds}; run auto once to store them",
+        auto_creds = auto_creds.display()
+    ))?;
+
+    let api_client = util::make_client_and_store()?;
+    let ApiClient(client, cookie_store) = &api_client;
+
+    login::perform_login(client, &credentials).await?;
+    write_cookies_to_disk_detailed(cookie_store.clone(), auto_cookies).await?;
+
+    say!("Logged in successfully.");
+
+    Ok(api_client)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SyncOutcome {
+    New,
+    Changed,
+    Unchanged,
+}
+
+async fn handle_sync(
+    timestamp: &str,
+    redo_login: bool,
+    skip_images: bool,
+    expiry_window: i64,
+) -> anyhow::Result<serde_json::Value> {
+    let mut api_client = login_non_interactive(redo_login).await?;
+    let mut books = crawl::get_books(&api_client).await?;
+
+    // An expired session just shows an empty shelf
+    if books.is_empty() && !redo_login && config::get().auto_creds().exists() {
+        say!("No books found; logging in again in case the session expired...");
+
+        api_client = login_non_interactive(true).await?;
+        books = crawl::get_books(&api_client).await?;
+    }
+
+    schema::save(config::get().auto_book_metadata(), &books)?;
+
+    say!("Crawled {count} books successfully.", count = books.len());
+
+    let mut library = Library::load()?;
+
+    for book in library.update_listing(&books) {
+        say!("No longer listed: {title}", title = book.title);
+    }
+
+    library.update_covers(&api_client).await?;
+    library.save()?;
+
+    warn_expiring(&library, expiry_window);
+
+    let mut cache = HttpCache::load()?;
+    let (mut new, mut changed, mut unchanged, mut failed) = (0, 0, 0, 0);
+
+    for book in &books {
+        interrupt::check()?;
+
+        say!("Syncing {title}...", title = book.title);
+
+        let previous = library.entry(book).book_data.clone();
+
+        let mut result = sync_book(
+            timestamp,
+            &api_client,
+            &mut cache,
+            book,
+            previous.as_deref(),
+            skip_images,
+        )
+        .await;
+
+        // The session may expire in the middle of a sync
+        if result.as_ref().is_err_and(sniff::is_session_expired)
+            && config::get().auto_creds().exists()
+        {
+            say!("The session expired; logging in again...");
+
+            api_client = login_non_interactive(true).await?;
+            result = sync_book(
+                timestamp,
+                &api_client,
+                &mut cache,
+                book,
+                previous.as_deref(),
+                skip_images,
+            )
+            .await;
+        }
+
+        cache.save()?;
+
+        let (outcome, book_data) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                say!("Failed to sync {title}: {e:#}", title = book.title);
+                output::event(
+                    "sync_failed",
+                    json!({ "id": book.id, "title": book.title, "error": format!("{e:#}") }),
+                );
+                failed += 1;
+                continue;
+            }
+        };
+
+        output::event(
+            "synced",
+            json!({
+                "id": book.id,
+                "title": book.title,
+                "outcome": outcome,
+                "book_data": book_data,
+            }),
+        );
+
+        match outcome {
+            SyncOutcome::New => new += 1,
+            SyncOutcome::Changed => changed += 1,
+            SyncOutcome::Unchanged => unchanged += 1,
+        }
+
+        let entry = library.entry(book);
+        entry.book_data = Some(book_data);
+        entry.last_synced = Some(timestamp.to_string());
+        library.save()?;
+    }
 
-    println!("Crawled books successfully.");
+    say!("Synced library: {new} new, {changed} changed, {unchanged} unchanged, {failed} failed.");
 
-    // Ask for which book to download
-    let selection = cli::book_selection(&books).unwrap();
+    if failed > 0 {
+        return Err(anyhow::anyhow!("Failed to sync {failed} books"));
+    }
+
+    Ok(json!({
+        "new": new,
+        "changed": changed,
+        "unchanged": unchanged,
+        "failed": failed,
+    }))
+}
+
+/// Prints a warning for every book expiring within the next `days` days.
+fn warn_expiring(library: &Library, days: i64) {
+    let today = chrono::Local::now().date_naive();
+
+    for book in library.expiring_within(today, days) {
+        let remaining = book.parsed_book.days_until_expiry(today).unwrap();
+
+        if remaining < 0 {
+            say!(
+                "Warning: {title} has expired.",
+                title = book.parsed_book.title
+            );
+        } else {
+            say!(
+                "Warning: {title} expires in {remaining} days.",
+                title = book.parsed_book.title
+            );
+        }
+    }
+}
+
+async fn handle_expiry() -> anyhow::Result<Vec<serde_json::Value>> {
+    let library = Library::load()?;
+    let today = chrono::Local::now().date_naive();
+    let mut books = Vec::new();
+
+    for book in library.by_expiry() {
+        let ParsedBook { id, title, .. } = &book.parsed_book;
+        let expiry = book.parsed_book.expiry();
+        let remaining = book.parsed_book.days_until_expiry(today);
 
-    dbg!(selection);
+        match (expiry, remaining) {
+            (Some(expiry), Some(remaining)) => say!("{expiry} ({remaining:>4} days): {title}"),
+            _ => say!("{:<10} {:>11}: {title}", "unknown", ""),
+        }
+
+        books.push(json!({
+            "id": id,
+            "title": title,
+            "expires_on": expiry,
+            "days_remaining": remaining,
+        }));
+    }
+
+    Ok(books)
+}
+
+fn handle_queue_add(
+    timestamp: &str,
+    action: JobAction,
+    selection: &BookSelection,
+    format: Option<ExportFormat>,
+    skip_images: bool,
+) -> anyhow::Result<Vec<Job>> {
+    let library = Library::load()?;
+    let books = library
+        .books
+        .iter()
+        .map(|book| book.parsed_book.clone())
+        .collect::<Vec<_>>();
+
+    let _edit = EditLock::acquire()?;
+    let mut queue = Queue::load()?;
+    let mut added = Vec::new();
+
+    for book in selection.select(&books)? {
+        let job = queue.add(action, book, format, skip_images, timestamp);
+
+        say!(
+            "Added job {id}: {action} {title}",
+            id = job.id,
+            action = job.action.name(),
+            title = book.title
+        );
+
+        added.push(job.clone());
+    }
+
+    queue.save()?;
+
+    Ok(added)
+}
+
+fn handle_queue_list() -> anyhow::Result<Vec<Job>> {
+    let queue = Queue::load()?;
+
+    if queue.jobs.is_empty() {
+        say!("The queue is empty.");
+    }
+
+    for job in &queue.jobs {
+        say!(
+            "{id:>3}: {status:<9} {action:<8} {title}",
+            id = job.id,
+            status = job.status.name(),
+            action = job.action.name(),
+            title = job.book.title
+        );
+
+        if let Some(error) = &job.error {
+            say!("     error: {error}");
+        }
+    }
+
+    Ok(queue.jobs)
+}
+
+fn handle_queue_cancel(ids: &[u64]) -> anyhow::Result<Vec<Job>> {
+    let _edit = EditLock::acquire()?;
+    let mut queue = Queue::load()?;
+    let mut cancelled = Vec::new();
+
+    for id in ids {
+        let job = queue.job_mut(*id)?;
+
+        match job.status {
+            JobStatus::Queued => {
+                job.status = JobStatus::Cancelled;
+                say!("Cancelled job {id}.");
+                cancelled.push(job.clone());
+            }
+            JobStatus::Running => {
+                job.status = JobStatus::Cancelled;
+                say!("Cancelled job {id}; if it's running, it still finishes first.");
+                cancelled.push(job.clone());
+            }
+            status => say!("Job {id} is already {status}.", status = status.name()),
+        }
+    }
+
+    queue.save()?;
+
+    Ok(cancelled)
+}
+
+fn handle_queue_retry(ids: &[u64], failed: bool) -> anyhow::Result<Vec<Job>> {
+    let _edit = EditLock::acquire()?;
+    let mut queue = Queue::load()?;
+
+    let ids = if failed {
+        queue
+            .jobs
+            .iter()
+            .filter(|job| job.status == JobStatus::Failed)
+            .map(|job| job.id)
+            .collect()
+    } else if ids.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No jobs given (use --failed to retry all failed jobs)"
+        ));
+    } else {
+        ids.to_vec()
+    };
+
+    let mut retried = Vec::new();
+
+    for id in ids {
+        let job = queue.job_mut(id)?;
+
+        match job.status {
+            // Keeps the timestamp, so whatever was fetched already is reused
+            JobStatus::Failed | JobStatus::Cancelled => {
+                job.status = JobStatus::Queued;
+                job.error = None;
+                say!("Queued job {id} again.");
+                retried.push(job.clone());
+            }
+            status => say!("Job {id} is {status}.", status = status.name()),
+        }
+    }
+
+    queue.save()?;
+
+    Ok(retried)
+}
+
+fn handle_queue_clear() -> anyhow::Result<serde_json::Value> {
+    let _edit = EditLock::acquire()?;
+    let mut queue = Queue::load()?;
+    let before = queue.jobs.len();
+
+    queue
+        .jobs
+        .retain(|job| !matches!(job.status, JobStatus::Done | JobStatus::Cancelled));
+    queue.save()?;
+
+    let removed = before - queue.jobs.len();
+    say!("Removed {removed} finished jobs.");
+
+    Ok(json!({ "removed": removed }))
+}
+
+/// Processes the queue until no jobs are left.
+///
+/// The queue is re-read before every job, so jobs can be added or cancelled
+/// while the worker runs.
+async fn handle_queue_run(timestamp: &str, redo_login: bool) -> anyhow::Result<serde_json::Value> {
+    let _worker = WorkerLock::acquire()?;
+
+    let mut api_client = None;
+    let mut cache = HttpCache::load()?;
+    let (mut done, mut failed) = (0, 0);
+
+    loop {
+        let edit = EditLock::acquire()?;
+        let mut queue = Queue::load()?;
+        let Some(job) = queue.next_job() else {
+            break;
+        };
+
+        if job.status == JobStatus::Running {
+            say!(
+                "Resuming interrupted job {id}: {action} {title}",
+                id = job.id,
+                action = job.action.name(),
+                title = job.book.title
+            );
+        } else {
+            say!(
+                "Starting job {id}: {action} {title}",
+                id = job.id,
+                action = job.action.name(),
+                title = job.book.title
+            );
+        }
+
+        job.status = JobStatus::Running;
+        job.attempts += 1;
+        let run_timestamp = job
+            .timestamp
+            .get_or_insert_with(|| timestamp.to_string())
+            .clone();
+        let job = job.clone();
+        queue.save()?;
+        drop(edit);
+
+        if job.action != JobAction::Export && api_client.is_none() {
+            api_client = Some(login_non_interactive(redo_login).await?);
+        }
+
+        let mut result = run_job(&job, &run_timestamp, api_client.as_ref(), &mut cache).await;
+
+        // Retry once with a fresh session (resuming from what was fetched already)
+        if result.as_ref().is_err_and(sniff::is_session_expired)
+            && config::get().auto_creds().exists()
+        {
+            say!("The session expired; logging in again...");
+
+            api_client = Some(login_non_interactive(true).await?);
+            result = run_job(&job, &run_timestamp, api_client.as_ref(), &mut cache).await;
+        }
+
+        cache.save()?;
+
+        // Reloaded (under the lock) to keep the changes made while the job ran
+        let _edit = EditLock::acquire()?;
+        let mut queue = Queue::load()?;
+        let stored = queue.job_mut(job.id)?;
+
+        // Not a failure: the job resumes (from its journal) on the next run
+        if interrupt::is_interrupted() && stored.status == JobStatus::Running {
+            stored.status = JobStatus::Queued;
+            queue.save()?;
+
+            say!(
+                "Stopped job {id}; it resumes on the next `queue run`.",
+                id = job.id
+            );
+            return Err(anyhow::anyhow!("Interrupted"));
+        }
+
+        // Cancelled while running
+        if stored.status != JobStatus::Running {
+            continue;
+        }
+
+        match result {
+            Ok(path) => {
+                say!("Finished job {id}.", id = job.id);
+                output::event("job_done", json!({ "id": job.id, "result": path }));
+
+                stored.status = JobStatus::Done;
+                stored.error = None;
+                stored.result = Some(path);
+                done += 1;
+            }
+            Err(e) => {
+                say!("Job {id} failed: {e:#}", id = job.id);
+                output::event(
+                    "job_failed",
+                    json!({ "id": job.id, "error": format!("{e:#}") }),
+                );
+
+                stored.status = JobStatus::Failed;
+                stored.error = Some(format!("{e:#}"));
+                failed += 1;
+            }
+        }
+
+        queue.save()?;
+    }
+
+    say!("Processed the queue: {done} done, {failed} failed.");
+
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{failed} jobs failed"));
+    }
+
+    Ok(json!({ "done": done, "failed": failed }))
+}
+
+/// Runs a job of the queue, returning the book data or export it wrote.
+async fn run_job(
+    job: &Job,
+    timestamp: &str,
+    client: Option<&ApiClient>,
+    cache: &mut HttpCache,
+) -> anyhow::Result<PathBuf> {
+    let book = &job.book;
+
+    if job.action == JobAction::Export {
+        let book_data = Library::load()?
+            .entry_ref(&book.id)
+            .and_then(|book| book.book_data.clone())
+            .with_context(|| format!("{title} wasn't downloaded yet", title = book.title))?;
+        let format = job.format.unwrap_or(config::get().export_format);
+
+        let exported = handle_export(timestamp, &book_data, format, None, None).await?;
+
+        return Ok(exported.path);
+    }
+
+    let client = client.context("Not logged in")?;
+
+    let previous = match job.action {
+        JobAction::Refresh => Library::load()?
+            .entry_ref(&book.id)
+            .and_then(|book| book.book_data.clone()),
+        _ => None,
+    };
+
+    let (_, book_data) = sync_book(
+        timestamp,
+        client,
+        cache,
+        book,
+        previous.as_deref(),
+        job.skip_images,
+    )
+    .await?;
+
+    let mut library = Library::load()?;
+    let entry = library.entry(book);
+    entry.book_data = Some(book_data.clone());
+    entry.last_synced = Some(timestamp.to_string());
+    library.save()?;
+
+    Ok(book_data)
+}
+
+/// Downloads a book, or refreshes it if there is a previous download.
+///
+/// Unchanged refreshes (same pages and, unless skipped, same images) are
+/// discarded again, keeping the previous run.
+async fn sync_book(
+    timestamp: &str,
+    client: &ApiClient,
+    cache: &mut HttpCache,
+    book: &ParsedBook,
+    previous: Option<&Path>,
+    skip_images: bool,
+) -> anyhow::Result<(SyncOutcome, PathBuf)> {
+    let (book_complete, book_data) = download_book(timestamp, client, cache, book, None).await?;
+
+    let outcome = match previous.filter(|previous| previous.exists()) {
+        None => SyncOutcome::New,
+        Some(previous) => {
+            let previous_book: BookComplete = schema::load(previous)?;
+
+            // A previous run with missing pages counts as changed
+            let is_changed = diff::diff_runs(&previous_book, &book_complete)
+                .map(|diffs| !diffs.is_empty())
+                .unwrap_or(true);
+
+            if is_changed {
+                SyncOutcome::Changed
+            } else if skip_images {
+                discard_run(&book_complete, &book_data)?;
+                say!("Book is unchanged (images not checked).");
+
+                return Ok((SyncOutcome::Unchanged, previous.to_path_buf()));
+            } else {
+                // The pages may stay the same while their images change
+                let previous_images = Library::load()?.image_run(&book.id);
+                download_images(timestamp, client, cache, &book_complete, None).await?;
+
+                if diff::same_images(&previous_book, &book_complete)? {
+                    discard_images(&book_complete, previous_images)?;
+                    discard_run(&book_complete, &book_data)?;
+                    say!("Book is unchanged.");
+
+                    return Ok((SyncOutcome::Unchanged, previous.to_path_buf()));
+                }
+
+                return Ok((SyncOutcome::Changed, book_data));
+            }
+        }
+    };
+
+    if !skip_images {
+        download_images(timestamp, client, cache, &book_complete, None).await?;
+    }
+
+    Ok((outcome, book_data))
+}
+
+/// Removes the pages and metadata of a download run written by `download_book`.
+fn discard_run(book: &BookComplete, book_data: &Path) -> anyhow::Result<()> {
+    let id = &book.parsed_book.id;
+    let timestamp = &book.timestamp;
+
+    let svg_path = config::get().svg_run(id, timestamp);
+
+    std::fs::remove_dir_all(svg_path)?;
+    std::fs::remove_file(book_data)?;
+    std::fs::remove_file(manifest::manifest_path(id, timestamp, ManifestKind::Pages))?;
 
     Ok(())
 }
 
-async fn handle_crawl_info(book_metadata: impl AsRef<Path>) -> anyhow::Result<()> {
-    let books: Vec<ParsedBook> = serde_json::from_reader(std::fs::File::open(book_metadata)?)?;
+/// Removes the images of a download run written by `download_images`,
+/// pointing the library at the `previous` images again.
+fn discard_images(book: &BookComplete, previous: Option<PathBuf>) -> anyhow::Result<()> {
+    let id = &book.parsed_book.id;
+    let timestamp = &book.timestamp;
 
-    println!("Found {} books:", books.len());
+    let manifest_path = manifest::manifest_path(id, timestamp, ManifestKind::Images);
+    let manifest: Manifest = schema::load(&manifest_path)?;
 
-    for (i, ParsedBook { title, .. }) in books.iter().enumerate() {
-        // Use one space of left padding for the index
-        println!("{i:>2}: {title}");
+    // Only remove the images, thumbnails of the run share the directory
+    for entry in &manifest.entries {
+        if entry.path.exists() {
+            std::fs::remove_file(&entry.path)?;
+        }
     }
 
+    let img_path = config::get().img_run(id, timestamp);
+
+    if std::fs::read_dir(&img_path)?.next().is_none() {
+        std::fs::remove_dir(&img_path)?;
+    }
+
+    std::fs::remove_file(manifest_path)?;
+    std::fs::remove_file(
+        config::get()
+            .meta_dir()
+            .join(format!("imgs_{id}_{timestamp}.json")),
+    )?;
+
+    let mut library = Library::load()?;
+    library.entry(&book.parsed_book).images = previous;
+    library.save()?;
+
     Ok(())
 }
 
+#[derive(Debug, Serialize)]
+/// A crawled book, with the index to download it by
+struct ListedBook {
+    index: usize,
+    #[serde(flatten)]
+    book: ParsedBook,
+    cover: Option<PathBuf>,
+}
+
+async fn handle_crawl_info(book_metadata: impl AsRef<Path>) -> anyhow::Result<Vec<ListedBook>> {
+    let books: Vec<ParsedBook> = schema::load(book_metadata)?;
+
+    let library = Library::load()?;
+
+    say!("Found {} books:", books.len());
+
+    let mut listed = Vec::new();
+
+    for (i, book) in books.into_iter().enumerate() {
+        // Use one space of left padding for the index
+        say!("{i:>2}: {title}", title = book.title);
+
+        let cover = library
+            .entry_ref(&book.id)
+            .and_then(|book| book.cover.clone());
+
+        if let Some(cover) = &cover {
+            say!("    cover: {cover}", cover = cover.display());
+        }
+
+        listed.push(ListedBook {
+            index: i,
+            book,
+            cover,
+        });
+    }
+
+    Ok(listed)
+}
+
 async fn handle_get_thumbs(
     now_timestamp: &str,
     login_cookies: impl AsRef<Path>,
     full_book_data: impl AsRef<Path>,
-) -> anyhow::Result<()> {
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<DownloadedFiles> {
     let client = util::load_cookies_from_json(login_cookies).await?;
-    let book: BookComplete = serde_json::from_reader(std::fs::File::open(full_book_data)?)?;
-    let prev_timestamp = &book.timestamp;
+    let book: BookComplete = schema::load(full_book_data)?;
 
     // "Open" the book (we don't actually need the response, just the cookies)
-    let _ = books::do_book_form_dance(&client, &(BASE_URL.to_string() + &book.parsed_book.url))
+    books::do_book_form_dance(&client, &(BASE_URL.to_string() + &book.parsed_book.url))
         .await
-        .unwrap();
+        .context("Failed to open the book")?;
 
-    let mut img_path = PathBuf::from("d5s/downloads/imgs");
-    img_path.push(&book.parsed_book.id);
-    img_path.push(now_timestamp);
+    let img_path = config::get().img_run(&book.parsed_book.id, now_timestamp);
 
     std::fs::create_dir_all(&img_path)?;
 
-    books::dl_thumbnails(&client, &book, &img_path).await?;
+    let mut cache = HttpCache::load()?;
+    let journal = manifest::open_journal(
+        &book.parsed_book.id,
+        now_timestamp,
+        ManifestKind::Thumbnails,
+    )?;
+    let entries =
+        books::dl_thumbnails(&client, &mut cache, &book, pages, &img_path, &journal).await?;
+    cache.save()?;
+
+    say!("Downloaded thumbnails successfully.");
+
+    let downloaded = DownloadedFiles {
+        book_id: book.parsed_book.id.clone(),
+        path: img_path,
+        files: entries.len(),
+    };
 
-    println!("Downloaded thumbnails successfully.");
+    manifest::write_manifest(&Manifest {
+        book_id: book.parsed_book.id.clone(),
+        timestamp: now_timestamp.to_string(),
+        kind: ManifestKind::Thumbnails,
+        entries,
+    })?;
 
-    Ok(())
+    say!("Wrote thumbnail manifest to disk.");
+
+    Ok(downloaded)
 }
 
 async fn handle_get_img(
     now_timestamp: &str,
     login_cookies: impl AsRef<Path>,
     full_book_data: impl AsRef<Path>,
-) -> anyhow::Result<()> {
-    let client = util::load_cookies_from_json(login_cookies).await?;
-    let book: BookComplete = serde_json::from_reader(std::fs::File::open(full_book_data)?)?;
-    let prev_timestamp = &book.timestamp;
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<DownloadedFiles> {
+    let mut client = util::load_cookies_from_json(&login_cookies).await?;
+    let book: BookComplete = schema::load(full_book_data)?;
+    let mut cache = HttpCache::load()?;
+
+    let mut result =
+        open_and_download_images(now_timestamp, &client, &mut cache, &book, pages).await;
+
+    // Retry once with a fresh session (resuming from what was fetched already)
+    if let Some(fresh_client) = relogin_if_expired(&result, login_cookies.as_ref()).await? {
+        client = fresh_client;
+        result = open_and_download_images(now_timestamp, &client, &mut cache, &book, pages).await;
+    }
 
-    // "Open" the book (we don't actually need the response, just the cookies)
-    let _ = books::do_book_form_dance(&client, &(BASE_URL.to_string() + &book.parsed_book.url))
+    cache.save()?;
+
+    result
+}
+
+/// "Opens" a book, then downloads its images (see `download_images`).
+async fn open_and_download_images(
+    now_timestamp: &str,
+    client: &ApiClient,
+    cache: &mut HttpCache,
+    book: &BookComplete,
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<DownloadedFiles> {
+    // We don't actually need the response, just the cookies
+    books::do_book_form_dance(client, &(BASE_URL.to_string() + &book.parsed_book.url))
         .await
-        .unwrap();
+        .context("Failed to open the book")?;
 
-    let mut svg_path = PathBuf::from("d5s/downloads/svgs");
-    svg_path.push(&book.parsed_book.id);
-    svg_path.push(prev_timestamp);
+    download_images(now_timestamp, client, cache, book, pages).await
+}
+
+/// Logs in again with the stored credentials if `result` failed because the
+/// session of `login_cookies` expired.
+///
+/// Returns the new client to retry with, or `None` if there's nothing to retry.
+/// Without stored credentials, the expiry is turned into an error explaining
+/// how to get a new session.
+async fn relogin_if_expired<T>(
+    result: &anyhow::Result<T>,
+    login_cookies: &Path,
+) -> anyhow::Result<Option<ApiClient>> {
+    let Err(e) = result else {
+        return Ok(None);
+    };
 
-    let mut img_path = PathBuf::from("d5s/downloads/imgs");
-    img_path.push(&book.parsed_book.id);
-    img_path.push(now_timestamp);
+    if !sniff::is_session_expired(e) {
+        return Ok(None);
+    }
+
+    if !config::get().auto_creds().exists() {
+        return Err(anyhow::anyhow!(
+            "{e:#}; run `login` to refresh {login_cookies} (or `auto` to store credentials, so d5s can log in again by itself)",
+            login_cookies = login_cookies.display()
+        ));
+    }
+
+    say!("The session expired; logging in again...");
+
+    login_non_interactive(true).await.map(Some)
+}
+
+/// Downloads the images of the pages of a (previously downloaded) book.
+///
+/// The book has to be "opened" using `books::do_book_form_dance` first.
+async fn download_images(
+    now_timestamp: &str,
+    client: &ApiClient,
+    cache: &mut HttpCache,
+    book: &BookComplete,
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<DownloadedFiles> {
+    let prev_timestamp = &book.timestamp;
+
+    let svg_path = config::get().svg_run(&book.parsed_book.id, prev_timestamp);
+
+    let img_path = config::get().img_run(&book.parsed_book.id, now_timestamp);
 
     std::fs::create_dir_all(&img_path)?;
 
-    let imgs = books::get_img_urls(&client, &book.parsed_book.id, &svg_path).await?;
+    let mut imgs = books::get_img_urls(client, &book.parsed_book.id, &svg_path).await?;
+    imgs.retain(|img| select::page_selected(pages, img.page_number));
 
-    let mut path = PathBuf::from("d5s/downloads/meta");
+    let mut path = config::get().meta_dir();
     path.push(format!(
         "imgs_{id}_{timestamp}.json",
         id = book.parsed_book.id,
         timestamp = now_timestamp
     ));
-    let mut file = std::fs::File::create(path)?;
-    serde_json::to_writer_pretty(&mut file, &imgs)?;
+    schema::save(path, &imgs)?;
 
-    println!("Wrote image metadata to disk.");
+    say!("Wrote image metadata to disk.");
 
-    books::fetch_img(&client, &imgs, img_path).await?;
+    let journal =
+        manifest::open_journal(&book.parsed_book.id, now_timestamp, ManifestKind::Images)?;
+    let entries = books::fetch_img(client, cache, &imgs, &img_path, &journal).await?;
 
-    println!("Downloaded images successfully.");
+    say!("Downloaded images successfully.");
 
-    Ok(())
+    let downloaded = DownloadedFiles {
+        book_id: book.parsed_book.id.clone(),
+        path: img_path,
+        files: entries.len(),
+    };
+
+    manifest::write_manifest(&Manifest {
+        book_id: book.parsed_book.id.clone(),
+        timestamp: now_timestamp.to_string(),
+        kind: ManifestKind::Images,
+        entries,
+    })?;
+
+    say!("Wrote image manifest to disk.");
+
+    // Partial downloads don't replace the latest (full) images
+    if pages.is_none() {
+        let mut library = Library::load()?;
+        library.entry(&book.parsed_book).images = Some(downloaded.path.clone());
+        library.save()?;
+    }
+
+    Ok(downloaded)
+}
+
+#[derive(Debug, Serialize)]
+struct ExportedBook {
+    book_id: String,
+    format: ExportFormat,
+    path: PathBuf,
+}
+
+async fn handle_export(
+    now_timestamp: &str,
+    full_book_data: impl AsRef<Path>,
+    format: ExportFormat,
+    img_dir: Option<&str>,
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<ExportedBook> {
+    let book: BookComplete = schema::load(full_book_data)?;
+
+    let svg_path = config::get().svg_run(&book.parsed_book.id, &book.timestamp);
+
+    let id = &book.parsed_book.id;
+
+    let templates = &config::get().templates;
+    let vars = TemplateVars::new(&book.parsed_book, &book.book_meta, now_timestamp);
+    let export_path = config::get()
+        .exports_dir()
+        .join(template::render(&templates.export_dir, &vars)?);
+
+    std::fs::create_dir_all(&export_path)?;
+
+    let mut library = Library::load()?;
+
+    // Default to the latest complete images downloaded for the book
+    let img_path = match img_dir {
+        Some(img_dir) => Some(PathBuf::from(img_dir)),
+        None => library.image_run(id),
+    };
+    let img_path = img_path.as_deref();
+
+    let cover = library
+        .entry_ref(id)
+        .and_then(|book| book.cover.clone())
+        .filter(|cover| cover.exists());
+    let cover = cover.as_deref();
+
+    let exported = match format {
+        ExportFormat::Html => {
+            export::export_html(&book, &svg_path, img_path, cover, pages, &export_path)?;
+            export_path.clone()
+        }
+        ExportFormat::Epub => {
+            let epub = export_path.join(template::render_file(
+                &templates.export_file,
+                &vars,
+                "epub",
+            )?);
+            if let Some(parent) = epub.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            export::export_epub(&book, &svg_path, img_path, cover, pages, &epub)?;
+
+            // So `serve` can find it
+            library.entry(&book.parsed_book).epub = Some(epub.clone());
+            library.save()?;
+
+            epub
+        }
+        ExportFormat::Pdfmarks => {
+            let pdfmarks = export_path.join(template::render_file(
+                &templates.export_file,
+                &vars,
+                "pdfmarks",
+            )?);
+            if let Some(parent) = pdfmarks.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            export::write_pdfmarks(&book, pages, &pdfmarks)?;
+            pdfmarks
+        }
+    };
+
+    say!(
+        "Exported book to {export_path}.",
+        export_path = export_path.display()
+    );
+
+    Ok(ExportedBook {
+        book_id: id.clone(),
+        format,
+        path: exported,
+    })
+}
+
+async fn handle_catalog(
+    now_timestamp: &str,
+    format: CatalogFormat,
+    selection: &BookSelection,
+) -> anyhow::Result<serde_json::Value> {
+    let library = Library::load()?;
+
+    let books = if selection.is_empty() {
+        library.books.iter().collect::<Vec<_>>()
+    } else {
+        let parsed_books = library
+            .books
+            .iter()
+            .map(|book| book.parsed_book.clone())
+            .collect::<Vec<_>>();
+
+        selection
+            .select(&parsed_books)?
+            .into_iter()
+            .filter_map(|book| library.entry_ref(&book.id))
+            .collect()
+    };
+
+    // The full metadata is only known for downloaded books
+    let mut book_metas = Vec::new();
+
+    for book in &books {
+        let book_meta = match &book.book_data {
+            Some(book_data) => {
+                let complete: BookComplete = schema::load(book_data)?;
+                Some(complete.book_meta)
+            }
+            None => None,
+        };
+
+        book_metas.push(book_meta);
+    }
+
+    let entries = books
+        .iter()
+        .zip(&book_metas)
+        .map(|(book, book_meta)| CatalogEntry {
+            parsed_book: &book.parsed_book,
+            book_meta: book_meta.as_ref(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut export_path = config::get().exports_dir().join("catalog");
+    export_path.push(now_timestamp);
+
+    std::fs::create_dir_all(&export_path)?;
+
+    match format {
+        CatalogFormat::Opf => {
+            for (book, entry) in books.iter().zip(&entries) {
+                let book_path = export_path.join(&book.parsed_book.id);
+                std::fs::create_dir_all(&book_path)?;
+
+                // Calibre picks up the cover next to the sidecar
+                let cover = book.cover.as_deref().filter(|cover| cover.exists());
+                let cover_name = match cover {
+                    Some(cover) => {
+                        let cover_name = format!(
+                            "cover.{extension}",
+                            extension = cover
+                                .extension()
+                                .and_then(|extension| extension.to_str())
+                                .unwrap_or("jpg")
+                        );
+                        std::fs::copy(cover, book_path.join(&cover_name))?;
+                        Some(cover_name)
+                    }
+                    None => None,
+                };
+
+                std::fs::write(
+                    book_path.join("metadata.opf"),
+                    catalog::opf_sidecar(entry, cover_name.as_deref()),
+                )?;
+            }
+        }
+        CatalogFormat::Csv => {
+            std::fs::write(export_path.join("library.csv"), catalog::csv(&entries))?;
+        }
+        CatalogFormat::Bibtex => {
+            let bibtex = entries
+                .iter()
+                .map(catalog::bibtex_entry)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            std::fs::write(export_path.join("library.bib"), bibtex)?;
+        }
+    }
+
+    say!(
+        "Exported the metadata of {count} books to {export_path}.",
+        count = entries.len(),
+        export_path = export_path.display()
+    );
+
+    Ok(json!({ "format": format, "path": export_path, "books": entries.len() }))
+}
+
+async fn handle_verify(
+    full_book_data: impl AsRef<Path>,
+    refetch: bool,
+    login_cookies: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let book: BookComplete = schema::load(full_book_data)?;
+
+    let manifests = manifest::find_manifests(&book.parsed_book.id)?;
+
+    if manifests.is_empty() {
+        say!(
+            "No manifests found for book {id}.",
+            id = book.parsed_book.id
+        );
+        return Ok(json!({ "book_id": book.parsed_book.id, "manifests": 0, "bad_files": [] }));
+    }
+
+    let client = match login_cookies {
+        Some(login_cookies) if refetch => {
+            let client = util::load_cookies_from_json(login_cookies).await?;
+
+            // "Open" the book (we don't actually need the response, just the cookies)
+            books::do_book_form_dance(&client, &(BASE_URL.to_string() + &book.parsed_book.url))
+                .await
+                .context("Failed to open the book for re-fetching")?;
+
+            Some(client)
+        }
+        _ => None,
+    };
+
+    let manifest_count = manifests.len();
+    let mut bad_files = Vec::new();
+    let mut refetch_failed = 0;
+
+    for mut manifest in manifests {
+        let mut changed = false;
+
+        say!(
+            "Verifying {kind:?} of run {timestamp} ({count} files)...",
+            kind = manifest.kind,
+            timestamp = manifest.timestamp,
+            count = manifest.entries.len()
+        );
+
+        for entry in manifest.entries.iter_mut() {
+            let status = entry.check()?;
+
+            if status == EntryStatus::Ok {
+                continue;
+            }
+
+            say!("{status:?}: {path}", path = entry.path.display());
+
+            let mut error = None;
+
+            if let Some(client) = &client {
+                // Keep going, so the other files are still re-fetched
+                match entry.refetch(client).await {
+                    Ok(refetched) => {
+                        *entry = refetched;
+                        changed = true;
+
+                        say!("Re-fetched {url}", url = entry.url);
+                    }
+                    Err(e) => {
+                        refetch_failed += 1;
+                        say!("Failed to re-fetch {url}: {e:#}", url = entry.url);
+                        error = Some(format!("{e:#}"));
+                    }
+                }
+            }
+
+            bad_files.push(json!({
+                "status": status,
+                "path": entry.path,
+                "refetched": client.is_some() && error.is_none(),
+                "error": error,
+            }));
+        }
+
+        if changed {
+            manifest::write_manifest(&manifest)?;
+        }
+    }
+
+    let bad_count = bad_files.len();
+
+    if bad_count == 0 {
+        say!("All files are intact.");
+    } else if client.is_some() {
+        say!(
+            "Re-fetched {refetched} of {bad_count} missing or corrupt files.",
+            refetched = bad_count - refetch_failed
+        );
+    } else {
+        say!("Found {bad_count} missing or corrupt files (use --refetch to fix them).");
+    }
+
+    Ok(json!({
+        "book_id": book.parsed_book.id,
+        "manifests": manifest_count,
+        "bad_files": bad_files,
+    }))
+}
+
+async fn handle_diff(
+    old_book_data: impl AsRef<Path>,
+    new_book_data: impl AsRef<Path>,
+) -> anyhow::Result<serde_json::Value> {
+    let old: BookComplete = schema::load(old_book_data)?;
+    let new: BookComplete = schema::load(new_book_data)?;
+
+    say!(
+        "Comparing runs {old} and {new} of {title}:",
+        old = old.timestamp,
+        new = new.timestamp,
+        title = new.book_meta.title
+    );
+
+    if old.book_meta.page_sizes.len() != new.book_meta.page_sizes.len() {
+        say!(
+            "Page count changed from {old} to {new}.",
+            old = old.book_meta.page_sizes.len(),
+            new = new.book_meta.page_sizes.len()
+        );
+    }
+
+    let diffs = diff::diff_runs(&old, &new)?;
+
+    for diff in &diffs {
+        let page = diff.page;
+
+        match &diff.change {
+            PageChange::Added => say!("{page:>4}: added"),
+            PageChange::Removed => say!("{page:>4}: removed"),
+            PageChange::Missing { old, new } => say!(
+                "{page:>4}: not downloaded by the {runs}",
+                runs = match (old, new) {
+                    (true, true) => "old and new run",
+                    (true, false) => "old run",
+                    _ => "new run",
+                }
+            ),
+            PageChange::Changed {
+                size,
+                svg,
+                imgs_added,
+                imgs_removed,
+                imgs_changed,
+            } => {
+                let mut details = Vec::new();
+
+                if *size {
+                    details.push("size".to_string());
+                }
+                if *svg {
+                    details.push("content".to_string());
+                }
+                if !imgs_added.is_empty() {
+                    details.push(format!("+{} images", imgs_added.len()));
+                }
+                if !imgs_removed.is_empty() {
+                    details.push(format!("-{} images", imgs_removed.len()));
+                }
+                if !imgs_changed.is_empty() {
+                    details.push(format!("{} images differ", imgs_changed.len()));
+                }
+
+                say!("{page:>4}: changed ({})", details.join(", "));
+            }
+        }
+    }
+
+    if diffs.is_empty() {
+        say!("No changes.");
+    } else {
+        say!("{count} pages differ.", count = diffs.len());
+    }
+
+    Ok(json!({
+        "old": old.timestamp,
+        "new": new.timestamp,
+        "page_counts": [old.book_meta.page_sizes.len(), new.book_meta.page_sizes.len()],
+        "pages": diffs,
+    }))
+}
+
+async fn handle_gc() -> anyhow::Result<serde_json::Value> {
+    let (removed, freed) = store::gc()?;
+
+    say!(
+        "Removed {removed} unused files ({mib:.1} MiB).",
+        mib = freed as f64 / (1024.0 * 1024.0)
+    );
+
+    Ok(json!({ "removed": removed, "freed_bytes": freed }))
+}
+
+fn handle_migrate(dry_run: bool) -> anyhow::Result<serde_json::Value> {
+    let config = config::get();
+
+    // The cookies are stored in the format of the cookie store, not as documents
+    let mut files = util::json_files(config.meta_dir())?;
+    files.extend(util::json_files(config.credentials_dir())?);
+    if config.http_cache_path().exists() {
+        files.push(config.http_cache_path());
+    }
+
+    let mut migrated = Vec::new();
+    let mut current = 0;
+    let mut skipped = Vec::new();
+
+    for file in files {
+        match schema::migrate_file(&file, dry_run)? {
+            Some(migration) if migration.from < migration.to => {
+                say!(
+                    "{verb} {file} ({kind}, version {from} -> {to})",
+                    verb = if dry_run { "Would migrate" } else { "Migrated" },
+                    file = file.display(),
+                    kind = migration.kind,
+                    from = migration.from,
+                    to = migration.to
+                );
+                migrated.push(json!({ "file": file, "migration": migration }));
+            }
+            Some(_) => current += 1,
+            None => {
+                say!("Skipped {file} (not recognized)", file = file.display());
+                skipped.push(file);
+            }
+        }
+    }
+
+    say!(
+        "{count} files {verb}, {current} already up to date.",
+        count = migrated.len(),
+        verb = if dry_run { "to migrate" } else { "migrated" }
+    );
+
+    Ok(json!({ "migrated": migrated, "current": current, "skipped": skipped }))
+}
+
+fn handle_config(init: bool) -> anyhow::Result<serde_json::Value> {
+    let config = config::get();
+    let path = config::default_config_path();
+
+    if init {
+        let path = path
+            .as_ref()
+            .context("No config directory on this system")?;
+
+        if path.exists() {
+            return Err(anyhow::anyhow!(
+                "{path} already exists",
+                path = path.display()
+            ));
+        }
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, serde_json::to_string_pretty(config)?)?;
+
+        say!("Wrote config file {path}.", path = path.display());
+    } else {
+        say!("{}", serde_json::to_string_pretty(config)?);
+    }
+
+    Ok(json!({ "config_file": path, "config": config }))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -340,37 +2102,105 @@ pub struct BookComplete {
     pub parsed_book: ParsedBook,
 }
 
+#[derive(Debug, Serialize)]
+/// A book downloaded by a command
+struct DownloadedBook {
+    id: String,
+    title: String,
+    book_data: PathBuf,
+}
+
+impl DownloadedBook {
+    fn new(book: &ParsedBook, book_data: &Path) -> Self {
+        DownloadedBook {
+            id: book.id.clone(),
+            title: book.title.clone(),
+            book_data: book_data.to_path_buf(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// The images or thumbnails downloaded for a book
+struct DownloadedFiles {
+    book_id: String,
+    path: PathBuf,
+    files: usize,
+}
+
 async fn handle_get_book(
     timestamp: &str,
     login_cookies: impl AsRef<Path>,
     book_metadata: impl AsRef<Path>,
-    index: usize,
-) -> anyhow::Result<()> {
-    let books: Vec<ParsedBook> = serde_json::from_reader(std::fs::File::open(book_metadata)?)?;
+    index: Option<usize>,
+    selection: &BookSelection,
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<Vec<DownloadedBook>> {
+    let books: Vec<ParsedBook> = schema::load(book_metadata)?;
 
-    println!("Attempting to download book idx={index}...");
+    let selected = match index {
+        Some(index) => {
+            say!("Attempting to download book idx={index}...");
 
-    let book = books.get(index).context("Invalid index")?;
-    println!("Found book: {title}", title = book.title);
+            vec![books.get(index).context("Invalid index")?]
+        }
+        None => selection.select(&books)?,
+    };
 
-    let client = util::load_cookies_from_json(login_cookies).await?;
+    let mut client = util::load_cookies_from_json(&login_cookies).await?;
+    let mut cache = HttpCache::load()?;
+    let mut library = Library::load()?;
+    let mut downloaded = Vec::new();
 
-    let url = BASE_URL.to_string() + &book.url;
-    let initial_book_html = books::do_book_form_dance(&client, &url).await.unwrap();
+    for book in selected {
+        say!("Found book: {title}", title = book.title);
 
-    write_cookies_to_disk(client.1.clone(), &timestamp, "do-book-form-dance")
-        .await
-        .unwrap();
+        let mut result = download_book(timestamp, &client, &mut cache, book, pages).await;
 
-    let mut path = PathBuf::from("d5s/downloads/meta");
-    path.push(format!("initial_book_{id}_{timestamp}.html", id = book.id));
-    let mut file = std::fs::File::create(path)?;
-    file.write_all(initial_book_html.as_bytes())?;
-    file.flush()?;
+        // Retry once with a fresh session (resuming from what was fetched already)
+        if let Some(fresh_client) = relogin_if_expired(&result, login_cookies.as_ref()).await? {
+            client = fresh_client;
+            result = download_book(timestamp, &client, &mut cache, book, pages).await;
+        }
+
+        cache.save()?;
+        let (_, book_data) = result?;
 
-    println!("Wrote initial book html to disk.");
+        downloaded.push(DownloadedBook::new(book, &book_data));
+
+        // Partial downloads don't replace the latest (full) download
+        if pages.is_some() {
+            continue;
+        }
 
-    let book_meta = books::extract_metadata_from_initial_html(&initial_book_html).unwrap();
+        let entry = library.entry(book);
+        entry.book_data = Some(book_data);
+        entry.last_synced = Some(timestamp.to_string());
+        library.save()?;
+    }
+
+    Ok(downloaded)
+}
+
+/// Downloads the metadata and the pages (without images) of a book.
+///
+/// Returns the full book data and the path of the JSON file it was written to.
+async fn download_book(
+    timestamp: &str,
+    client: &ApiClient,
+    cache: &mut HttpCache,
+    book: &ParsedBook,
+    pages: Option<&PageRanges>,
+) -> anyhow::Result<(BookComplete, PathBuf)> {
+    output::event(
+        "download_started",
+        json!({ "id": book.id, "title": book.title }),
+    );
+
+    let url = BASE_URL.to_string() + &book.url;
+    let initial_book_html = books::do_book_form_dance(client, &url).await?;
+
+    let book_meta = books::extract_metadata_from_initial_html(&initial_book_html)?;
 
     let book_complete = BookComplete {
         timestamp: timestamp.to_string(),
@@ -378,131 +2208,113 @@ async fn handle_get_book(
         parsed_book: book.clone(),
     };
 
-    let sanitized_title = book_meta
-        .title
-        .chars()
-        .filter(|c| c.is_ascii_alphanumeric() || *c == ' ')
-        .collect::<String>()
-        .replace(" ", "_")
-        .replace("/", "_")
-        .replace("\\", "_")
-        .replace("__", "_")
-        .replace("__", "_")
-        .replace("__", "_")
-        .replace("__", "_")
-        .replace("__", "_");
-
-    let mut path = PathBuf::from("d5s/downloads/meta");
-    path.push(format!(
-        "book_data_{id}_{timestamp}_{name}.json",
-        id = book.id,
-        name = &sanitized_title[..min(sanitized_title.len(), 85)]
-    ));
-    let mut file = std::fs::File::create(path)?;
-    serde_json::to_writer_pretty(&mut file, &book_complete)?;
+    let vars = TemplateVars::new(book, &book_meta, timestamp);
+    let book_data = config::get().meta_dir().join(template::render_file(
+        &config::get().templates.book_data,
+        &vars,
+        "json",
+    )?);
+
+    if let Some(parent) = book_data.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    schema::save(&book_data, &book_complete)?;
 
-    println!("Wrote book metadata to disk.");
+    say!("Wrote book metadata to disk.");
 
     // let url = "https://a.digi4school.at/ebook/".to_string() + &book.id + "/" + &book.code + "/";
 
-    dbg!(&url);
-    let _version = books::do_version_check(&client, &book).await.unwrap();
+    let _version = books::do_version_check(client, book).await?;
 
     let url = "https://a.digi4school.at/ebook/".to_string() + &book.id + "/";
 
     // Create the directory for the book
-    let mut path = PathBuf::from("d5s/downloads/svgs");
-    path.push(&book.id);
-    path.push(timestamp);
+    let path = config::get().svg_run(&book.id, timestamp);
 
     std::fs::create_dir_all(&path)?;
 
-    books::do_download(&client, &url, &book_meta, &path)
-        .await
-        .unwrap();
+    let journal = manifest::open_journal(&book.id, timestamp, ManifestKind::Pages)?;
+    let entries =
+        books::do_download(client, cache, &url, &book_meta, pages, &path, &journal).await?;
 
-    println!("Downloaded book successfully (without images).");
+    say!("Downloaded book successfully (without images).");
 
-    // Save cookies to disk
-    write_cookies_to_disk(client.1.clone(), &timestamp, "do-download")
-        .await
-        .unwrap();
+    manifest::write_manifest(&Manifest {
+        book_id: book.id.clone(),
+        timestamp: timestamp.to_string(),
+        kind: ManifestKind::Pages,
+        entries,
+    })?;
 
-    Ok(())
-}
+    say!("Wrote page manifest to disk.");
 
-async fn handle_login(timestamp: &str, path: impl AsRef<Path>) -> anyhow::Result<ApiClient> {
-    let ApiClient(client, cookie_store) = util::make_client_and_store();
+    output::event("book_downloaded", DownloadedBook::new(book, &book_data));
 
-    let credentials = login::get_credentials(path).await.unwrap();
+    Ok((book_complete, book_data))
+}
 
-    write_cookies_to_disk(cookie_store.clone(), &timestamp, "empty")
-        .await
-        .unwrap();
+async fn handle_login(
+    timestamp: &str,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<serde_json::Value> {
+    let ApiClient(client, cookie_store) = util::make_client_and_store()?;
 
-    login::do_init_get(cookie_store.clone()).await.unwrap();
+    let credentials = login::get_credentials(path).await?;
 
-    write_cookies_to_disk(cookie_store.clone(), &timestamp, "init-get")
-        .await
-        .unwrap();
+    login::do_init_get(&client).await?;
 
-    login::perform_login(&client, &credentials).await.unwrap();
+    login::perform_login(&client, &credentials).await?;
 
-    write_cookies_to_disk(cookie_store.clone(), &timestamp, "login")
-        .await
-        .unwrap();
+    let cookies = write_cookies_to_disk(cookie_store.clone(), timestamp, "login").await?;
 
-    println!("Logged in successfully.");
+    say!("Logged in successfully.");
 
-    Ok(ApiClient(client, cookie_store))
+    Ok(json!({ "cookies": cookies }))
 }
 
 #[deprecated]
-async fn handle_resume(timestamp: &str, path: impl AsRef<Path>) -> anyhow::Result<ApiClient> {
-    let client = util::load_cookies_from_json(path).await?;
-
-    write_cookies_to_disk(client.1.clone(), &timestamp, "load-cookies")
-        .await
-        .unwrap();
-
-    Ok(client)
+#[allow(dead_code)]
+async fn handle_resume(path: impl AsRef<Path>) -> anyhow::Result<ApiClient> {
+    util::load_cookies_from_json(path).await
 }
 
-async fn handle_crawl_books(timestamp: &str, path: impl AsRef<Path>) -> anyhow::Result<ApiClient> {
+async fn handle_crawl_books(
+    timestamp: &str,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<Vec<ParsedBook>> {
     let client = util::load_cookies_from_json(path).await?;
 
-    write_cookies_to_disk(client.1.clone(), &timestamp, "load-cookies")
-        .await
-        .unwrap();
-
-    let books = crawl::get_books(&client).await.unwrap();
+    let books = crawl::get_books(&client).await?;
 
-    let mut path = PathBuf::from("d5s/downloads/meta");
+    let mut path = config::get().meta_dir();
     path.push(format!("{timestamp}_books.json"));
-    let file = std::fs::File::create(path)?;
-    let mut file = std::io::BufWriter::new(file);
+    schema::save(path, &books)?;
 
-    serde_json::to_writer_pretty(&mut file, &books)?;
+    say!("Crawled books successfully.");
 
-    println!("Crawled books successfully.");
+    let mut library = Library::load()?;
+    library.update_listing(&books);
+    library.update_covers(&client).await?;
+    library.save()?;
 
-    Ok(client)
+    Ok(books)
 }
 
 async fn write_cookies_to_disk(
     cookie_store: Arc<CookieStoreMutex>,
     timestamp: &str,
     name: &str,
-) -> anyhow::Result<()> {
-    let mut path = PathBuf::from("d5s/keys/cookies");
+) -> anyhow::Result<PathBuf> {
+    let mut path = config::get().cookies_dir();
     path.push(format!("{timestamp}_{name}.json"));
-    let file = std::fs::File::create(path)?;
+    let file = std::fs::File::create(&path)?;
     let mut file = std::io::BufWriter::new(file);
 
     let cookie_store = cookie_store.lock().unwrap();
     cookie_store.save_json(&mut file).unwrap();
 
-    Ok(())
+    Ok(path)
 }
 
 async fn write_cookies_to_disk_detailed(
@@ -0,0 +1,233 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use reqwest::header::{HeaderMap, CONTENT_TYPE, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config, record, schema,
+    sniff::{self, Expected},
+    store, throttle,
+    util::ApiClient,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// What a download run fetched
+pub enum ManifestKind {
+    Pages,
+    Images,
+    Thumbnails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The files fetched by one download run, for verifying them later on
+pub struct Manifest {
+    pub book_id: String,
+    pub timestamp: String,
+    pub kind: ManifestKind,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryStatus {
+    Ok,
+    Missing,
+    Corrupt,
+}
+
+impl ManifestEntry {
+    /// Describes a file which was just downloaded from `url`.
+    pub fn new(url: &str, path: impl AsRef<Path>, headers: &HeaderMap, bytes: &[u8]) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        ManifestEntry {
+            path: path.as_ref().to_path_buf(),
+            size: bytes.len() as u64,
+            sha256: sha256_hex(bytes),
+            url: url.to_string(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    /// Re-hashes the file and compares it to the manifest.
+    pub fn check(&self) -> anyhow::Result<EntryStatus> {
+        if !self.path.exists() {
+            return Ok(EntryStatus::Missing);
+        }
+
+        let bytes = std::fs::read(&self.path)?;
+
+        if bytes.len() as u64 != self.size || sha256_hex(&bytes) != self.sha256 {
+            Ok(EntryStatus::Corrupt)
+        } else {
+            Ok(EntryStatus::Ok)
+        }
+    }
+
+    /// Downloads the file again, returning the updated entry.
+    ///
+    /// (An image may end up with a different extension, see `sniff::typed_path`.)
+    pub async fn refetch(&self, ApiClient(client, _): &ApiClient) -> anyhow::Result<Self> {
+        throttle::wait_for_window().await?;
+
+        let response = record::send(client.get(&self.url)).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Got non-success status code: {}",
+                response.status()
+            ));
+        };
+
+        let headers = response.headers().clone();
+        let bytes = throttle::read_body(response).await?;
+
+        let expected = Expected::from_path(&self.path);
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let file_type = sniff::check(&self.url, &bytes, content_type, expected)?;
+        let path = sniff::typed_path(&self.path, file_type, expected);
+
+        store::save(&path, &bytes).await?;
+
+        // Don't leave the broken file behind under its old name
+        if path != self.path && self.path.exists() {
+            tokio::fs::remove_file(&self.path).await?;
+        }
+
+        Ok(ManifestEntry::new(&self.url, path, &headers, &bytes))
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+impl ManifestKind {
+    const fn name(self) -> &'static str {
+        match self {
+            ManifestKind::Pages => "pages",
+            ManifestKind::Images => "imgs",
+            ManifestKind::Thumbnails => "thumbs",
+        }
+    }
+}
+
+/// The path of the manifest of a download run, next to the `BookComplete` JSON.
+pub fn manifest_path(book_id: &str, timestamp: &str, kind: ManifestKind) -> PathBuf {
+    config::get().meta_dir().join(format!(
+        "manifest_{book_id}_{timestamp}_{kind}.json",
+        kind = kind.name()
+    ))
+}
+
+/// The path of the journal of the files fetched so far by an unfinished download run.
+pub fn journal_path(book_id: &str, timestamp: &str, kind: ManifestKind) -> PathBuf {
+    config::get().meta_dir().join(format!(
+        "progress_{book_id}_{timestamp}_{kind}.jsonl",
+        kind = kind.name()
+    ))
+}
+
+/// The journal for fetching the files of a download run (see `cache::fetch_all`).
+///
+/// If the run already got a manifest (i.e. it's being resumed after fetching
+/// these files), the journal starts out with its entries.
+pub fn open_journal(book_id: &str, timestamp: &str, kind: ManifestKind) -> anyhow::Result<PathBuf> {
+    let journal = journal_path(book_id, timestamp, kind);
+    let manifest = manifest_path(book_id, timestamp, kind);
+
+    if manifest.exists() && !journal.exists() {
+        let manifest: Manifest = schema::load(&manifest)?;
+        let mut file = std::fs::File::create(&journal)?;
+
+        for entry in &manifest.entries {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+    }
+
+    Ok(journal)
+}
+
+pub fn write_manifest(manifest: &Manifest) -> anyhow::Result<PathBuf> {
+    let path = manifest_path(&manifest.book_id, &manifest.timestamp, manifest.kind);
+
+    schema::save(&path, manifest)?;
+
+    Ok(path)
+}
+
+/// Loads the manifests of all download runs.
+pub fn all_manifests() -> anyhow::Result<Vec<Manifest>> {
+    let mut manifests = Vec::new();
+
+    for file in std::fs::read_dir(config::get().meta_dir())? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        if !name.starts_with("manifest_") || !name.ends_with(".json") {
+            continue;
+        }
+
+        manifests.push(schema::load(&path)?);
+    }
+
+    Ok(manifests)
+}
+
+/// The entries of the journals of all unfinished download runs.
+///
+/// A line cut off by a crash is skipped.
+pub fn all_journal_entries() -> anyhow::Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+
+    for file in std::fs::read_dir(config::get().meta_dir())? {
+        let path = file?.path();
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        if !name.starts_with("progress_") || !name.ends_with(".jsonl") {
+            continue;
+        }
+
+        entries.extend(
+            std::fs::read_to_string(&path)?
+                .lines()
+                .filter_map(|line| serde_json::from_str::<ManifestEntry>(line).ok()),
+        );
+    }
+
+    Ok(entries)
+}
+
+/// Loads the manifests of all download runs of a book.
+pub fn find_manifests(book_id: &str) -> anyhow::Result<Vec<Manifest>> {
+    let mut manifests = all_manifests()?
+        .into_iter()
+        .filter(|manifest| manifest.book_id == book_id)
+        .collect::<Vec<_>>();
+
+    manifests.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+    Ok(manifests)
+}
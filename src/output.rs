@@ -0,0 +1,72 @@
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable messages
+    #[default]
+    Text,
+    /// A single JSON document with the result (or the error) of the command
+    Json,
+    /// One JSON object per line: progress events, then the result (or the error)
+    Ndjson,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+pub fn set_format(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> OutputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Prints a human-readable message.
+///
+/// Goes to stderr with machine-readable output, so stdout stays parseable.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::format() == $crate::output::OutputFormat::Text {
+            println!($($arg)*);
+        } else {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use say;
+
+/// Emits a progress event (only printed as NDJSON).
+pub fn event(event: &str, data: impl Serialize) {
+    if format() == OutputFormat::Ndjson {
+        println!("{}", json!({ "event": event, "data": data }));
+    }
+}
+
+/// Emits the result of a command (the messages already said it all as text).
+pub fn result(data: impl Serialize) {
+    match format() {
+        OutputFormat::Text => {}
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({ "ok": true, "result": data })).unwrap()
+        ),
+        OutputFormat::Ndjson => event("result", data),
+    }
+}
+
+/// Emits the error a command failed with.
+pub fn error(e: &anyhow::Error) {
+    match format() {
+        OutputFormat::Text => eprintln!("Error: {e:?}"),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({ "ok": false, "error": format!("{e:#}") }))
+                .unwrap()
+        ),
+        OutputFormat::Ndjson => event("error", json!({ "message": format!("{e:#}") })),
+    }
+}
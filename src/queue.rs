@@ -0,0 +1,198 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config, crawl::ParsedBook, export::ExportFormat, schema};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// What a job does with its book
+pub enum JobAction {
+    /// Download the book (as a new run)
+    Download,
+    /// Download the book again, keeping the previous run if it's unchanged
+    Refresh,
+    /// Export the latest download of the book
+    Export,
+}
+
+impl JobAction {
+    pub const fn name(self) -> &'static str {
+        match self {
+            JobAction::Download => "download",
+            JobAction::Refresh => "refresh",
+            JobAction::Export => "export",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    /// Being processed (or interrupted, if no worker is running)
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub const fn name(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub action: JobAction,
+    pub book: ParsedBook,
+    /// The format to export to (default: export_format of the config)
+    pub format: Option<ExportFormat>,
+    /// Only download the pages, not the images
+    pub skip_images: bool,
+    pub status: JobStatus,
+    /// When the job was added
+    pub added: String,
+    /// The timestamp of the run, kept so an interrupted job resumes the same run
+    pub timestamp: Option<String>,
+    pub attempts: u32,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    /// The book data or export written by the job
+    pub result: Option<PathBuf>,
+}
+
+/// Held by `queue run` while it processes the queue, so only one worker runs
+/// at a time (and a running job is never mistaken for an interrupted one).
+///
+/// Released when dropped.
+pub struct WorkerLock {
+    _file: std::fs::File,
+}
+
+impl WorkerLock {
+    /// Fails if another `queue run` is processing the queue.
+    pub fn acquire() -> anyhow::Result<Self> {
+        let path = config::get().queue_path().with_extension("lock");
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(WorkerLock { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+                "The queue is already being processed by another `queue run`"
+            )),
+            Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// Held (exclusively) around every load, change and save of the queue file,
+/// so `queue add`/`cancel`/`retry`/`clear` don't race the worker.
+pub struct EditLock {
+    _file: std::fs::File,
+}
+
+impl EditLock {
+    /// Waits until no other process is changing the queue.
+    pub fn acquire() -> anyhow::Result<Self> {
+        let path = config::get().queue_path().with_extension("edit.lock");
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock()?;
+
+        Ok(EditLock { _file: file })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+/// The jobs added by `queue add`, processed in order by `queue run`
+pub struct Queue {
+    next_id: u64,
+    pub jobs: Vec<Job>,
+}
+
+impl Queue {
+    /// Loads the queue from disk (or starts an empty one).
+    pub fn load() -> anyhow::Result<Self> {
+        let path = config::get().queue_path();
+
+        if !path.exists() {
+            return Ok(Queue::default());
+        }
+
+        schema::load(path)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        schema::save(config::get().queue_path(), self)
+    }
+
+    pub fn add(
+        &mut self,
+        action: JobAction,
+        book: &ParsedBook,
+        format: Option<ExportFormat>,
+        skip_images: bool,
+        added: &str,
+    ) -> &Job {
+        self.next_id += 1;
+
+        self.jobs.push(Job {
+            id: self.next_id,
+            action,
+            book: book.clone(),
+            format,
+            skip_images,
+            status: JobStatus::Queued,
+            added: added.to_string(),
+            timestamp: None,
+            attempts: 0,
+            error: None,
+            result: None,
+        });
+
+        self.jobs.last().unwrap()
+    }
+
+    pub fn job_mut(&mut self, id: u64) -> anyhow::Result<&mut Job> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or_else(|| anyhow::anyhow!("No job with ID {id}"))
+    }
+
+    /// The job to process next: an interrupted one, or else the oldest queued one.
+    ///
+    /// Only call this while holding the `WorkerLock`, otherwise a job another
+    /// worker is running looks interrupted.
+    pub fn next_job(&mut self) -> Option<&mut Job> {
+        let index = self
+            .jobs
+            .iter()
+            .position(|job| job.status == JobStatus::Running)
+            .or_else(|| {
+                self.jobs
+                    .iter()
+                    .position(|job| job.status == JobStatus::Queued)
+            })?;
+
+        Some(&mut self.jobs[index])
+    }
+}
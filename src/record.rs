@@ -0,0 +1,693 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use anyhow::Context;
+use base64::Engine;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION,
+        TRANSFER_ENCODING,
+    },
+    redirect::Policy,
+    Method, Request, RequestBuilder, Response, StatusCode, Url, Version,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, login::Credentials, output::say, schema, throttle};
+
+/// How many redirects to follow (like reqwest does by default)
+const MAX_REDIRECTS: usize = 10;
+
+const REDACTED: &str = "[redacted]";
+
+/// Headers whose values are never recorded
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "proxy-authorization",
+];
+
+/// Parts of form field (and query parameter) names whose values are never recorded
+///
+/// Besides the login, this covers the LTI launch forms of the book viewer
+/// (`lis_person_*`, `user_id`, `oauth_signature`, ...), which carry the name
+/// and email of the user and signed tokens.
+const SECRET_FIELDS: [&str; 8] = [
+    "email",
+    "password",
+    "code",
+    "token",
+    "secret",
+    "signature",
+    "user_id",
+    "lis_person",
+];
+
+lazy_static! {
+    /// An input of a form (like `<input type="hidden" name="..." value="...">`)
+    static ref FORM_INPUT_REGEX: Regex = Regex::new(r"(?i)<input\b[^>]*>").unwrap();
+    /// The name or value of an input, in either kind of quotes
+    static ref INPUT_ATTRIBUTE_REGEX: Regex =
+        Regex::new(r#"(?i)\b((name|value)\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap();
+}
+
+/// Records all HTTP traffic of a run, for writing it to a HAR file
+struct Recorder {
+    path: PathBuf,
+    entries: Mutex<Vec<Entry>>,
+    /// Values to redact wherever they show up (e.g. the password)
+    secrets: Mutex<Vec<String>>,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// Starts recording all requests (written to `path` by `finish`).
+pub fn start(path: impl AsRef<Path>) {
+    let _ = RECORDER.set(Recorder {
+        path: path.as_ref().to_path_buf(),
+        entries: Mutex::new(Vec::new()),
+        secrets: Mutex::new(Vec::new()),
+    });
+}
+
+/// The redirect policy for clients (`send` follows redirects itself while recording).
+pub fn redirect_policy() -> Policy {
+    if RECORDER.get().is_some() {
+        Policy::none()
+    } else {
+        Policy::default()
+    }
+}
+
+/// Redacts the stored login of the automatic modes.
+///
+/// Responses may show the email even when logging in with cookies only, so
+/// this doesn't rely on `login::perform_login` running.
+pub fn redact_stored_credentials() {
+    if RECORDER.get().is_none() {
+        return;
+    }
+
+    if let Ok(credentials) = schema::load::<Credentials>(config::get().auto_creds()) {
+        redact(&credentials.email);
+        redact(&credentials.password);
+    }
+}
+
+/// Makes sure `secret` (like the password) never ends up in the recording.
+pub fn redact(secret: &str) {
+    if let Some(recorder) = RECORDER.get() {
+        if !secret.is_empty() {
+            recorder.secrets.lock().unwrap().push(secret.to_string());
+        }
+    }
+}
+
+/// Writes the recording (if there is one) to its HAR file.
+pub fn finish() -> anyhow::Result<()> {
+    let Some(recorder) = RECORDER.get() else {
+        return Ok(());
+    };
+
+    let entries = std::mem::take(&mut *recorder.entries.lock().unwrap());
+    let count = entries.len();
+
+    let har = Har {
+        log: Log {
+            version: "1.2".to_string(),
+            creator: Creator {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            entries,
+        },
+    };
+
+    let mut json = serde_json::to_string_pretty(&har)?;
+
+    // Wherever they show up (even in requests made before they were known)
+    for secret in recorder.secrets.lock().unwrap().iter() {
+        let escaped = serde_json::to_string(secret)?;
+        let escaped = &escaped[1..escaped.len() - 1];
+
+        json = json
+            .replace(escaped, REDACTED)
+            .replace(&form_encode(secret), REDACTED);
+    }
+
+    std::fs::write(&recorder.path, json)?;
+
+    say!(
+        "Recorded {count} requests to {path}.",
+        path = recorder.path.display()
+    );
+
+    Ok(())
+}
+
+/// Sends a request, recording it (and every redirect) while recording.
+///
+/// While replaying, the response comes from the replayed capture instead.
+pub async fn send(request: RequestBuilder) -> anyhow::Result<Response> {
+    if RECORDER.get().is_none() && REPLAY.get().is_none() {
+        return Ok(request.send().await?);
+    }
+
+    let (client, request) = request.build_split();
+    let mut request = request?;
+
+    for redirects in 0.. {
+        let started = chrono::Local::now();
+        let timer = Instant::now();
+
+        let har_request = har_request(&request);
+        let url = request.url().clone();
+        let retry = request.try_clone();
+
+        let (status, version, headers, body) = match REPLAY.get() {
+            Some(replay) => replay.respond(request.method(), &url)?,
+            None => {
+                let response = client.execute(request).await?;
+
+                let status = response.status();
+                let version = response.version();
+                let headers = response.headers().clone();
+
+                // The body is read here already, so `limit_rate` has to apply here
+                (
+                    status,
+                    version,
+                    headers,
+                    throttle::read_body(response).await?,
+                )
+            }
+        };
+
+        let location = if status.is_redirection() {
+            headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok())
+        } else {
+            None
+        };
+
+        if let Some(recorder) = RECORDER.get() {
+            let elapsed = timer.elapsed().as_secs_f64() * 1000.0;
+
+            recorder.entries.lock().unwrap().push(Entry {
+                started_date_time: started.to_rfc3339(),
+                time: elapsed,
+                request: har_request,
+                response: har_response(status, version, &headers, &body, location.as_ref()),
+                cache: Empty {},
+                timings: Timings {
+                    send: 0.0,
+                    wait: elapsed,
+                    receive: 0.0,
+                },
+            });
+        }
+
+        match location {
+            Some(location) if redirects < MAX_REDIRECTS => {
+                // Like browsers, only 307 and 308 repeat the request as is
+                request = match (status, retry) {
+                    (
+                        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT,
+                        Some(mut retry),
+                    ) => {
+                        *retry.url_mut() = location;
+                        retry
+                    }
+                    _ => Request::new(Method::GET, location),
+                };
+            }
+            Some(_) => return Err(anyhow::anyhow!("Too many redirects")),
+            None => {
+                let mut response = http::Response::builder().status(status).version(version);
+                response.headers_mut().unwrap().extend(headers);
+
+                if REPLAY.get().is_none() {
+                    response = response.extension(throttle::Throttled);
+                }
+
+                return Ok(response.body(body)?.into());
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+/// The recorded responses, by method and URL (in the order they were recorded)
+struct Replay {
+    responses: Mutex<HashMap<(String, String), VecDeque<HarResponse>>>,
+}
+
+static REPLAY: OnceLock<Replay> = OnceLock::new();
+
+/// Serves all requests from a HAR file instead of the network.
+pub fn replay(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+
+    let read = || -> anyhow::Result<Har> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    };
+    let har = read().with_context(|| format!("Failed to load {path}", path = path.display()))?;
+
+    let _ = REPLAY.set(Replay::new(har));
+
+    Ok(())
+}
+
+impl Replay {
+    /// Keys the responses by their scrubbed URLs, like `har_request` records them
+    /// (captures of browsers contain the real ones).
+    fn new(har: Har) -> Self {
+        let mut responses = HashMap::<_, VecDeque<_>>::new();
+        for entry in har.log.entries {
+            let url = match Url::parse(&entry.request.url) {
+                Ok(url) => scrub_url(&url).to_string(),
+                Err(_) => entry.request.url,
+            };
+
+            responses
+                .entry((entry.request.method, url))
+                .or_default()
+                .push_back(entry.response);
+        }
+
+        Replay {
+            responses: Mutex::new(responses),
+        }
+    }
+
+    /// The next recorded response to a request (the last one repeats).
+    fn respond(
+        &self,
+        method: &Method,
+        url: &Url,
+    ) -> anyhow::Result<(StatusCode, Version, HeaderMap, Bytes)> {
+        let url = scrub_url(url).to_string();
+
+        let mut responses = self.responses.lock().unwrap();
+        let queue = responses
+            .get_mut(&(method.to_string(), url.clone()))
+            .filter(|queue| !queue.is_empty())
+            .with_context(|| format!("No recorded response to {method} {url}"))?;
+
+        let response = match queue.len() {
+            1 => queue[0].clone(),
+            _ => queue.pop_front().unwrap(),
+        };
+
+        let mut headers = HeaderMap::new();
+        for header in &response.headers {
+            // The body is complete (and may have changed in length by redacting)
+            if header.name.eq_ignore_ascii_case(CONTENT_LENGTH.as_str())
+                || header.name.eq_ignore_ascii_case(TRANSFER_ENCODING.as_str())
+            {
+                continue;
+            }
+
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(header.name.as_bytes()),
+                HeaderValue::from_str(&header.value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        let body = match response.content.encoding.as_deref() {
+            Some("base64") => base64::engine::general_purpose::STANDARD
+                .decode(&response.content.text)?
+                .into(),
+            _ => Bytes::from(response.content.text),
+        };
+
+        Ok((
+            StatusCode::from_u16(response.status)?,
+            Version::HTTP_11,
+            headers,
+            body,
+        ))
+    }
+}
+
+fn har_headers(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.to_string(),
+            value: if SECRET_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            },
+        })
+        .collect()
+}
+
+/// Redacts the values of secret fields of a query string or form.
+fn scrub_form(form: &str) -> String {
+    let fields = form
+        .split('&')
+        .map(|field| match field.split_once('=') {
+            Some((name, _)) if is_secret_field(name) => format!("{name}={REDACTED}"),
+            _ => field.to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    fields.join("&")
+}
+
+/// Redacts the values of secret query parameters.
+fn scrub_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    if let Some(query) = url.query().map(scrub_form) {
+        url.set_query(Some(&query));
+    }
+
+    url
+}
+
+fn har_request(request: &Request) -> HarRequest {
+    let url = scrub_url(request.url());
+
+    let mime_type = content_type(request.headers());
+    let body = request.body().and_then(|body| body.as_bytes());
+
+    HarRequest {
+        method: request.method().to_string(),
+        url: url.to_string(),
+        http_version: http_version(request.version()),
+        cookies: Vec::new(),
+        headers: har_headers(request.headers()),
+        query_string: url
+            .query_pairs()
+            .map(|(name, value)| NameValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        post_data: body.map(|body| PostData {
+            text: match mime_type.as_str() {
+                "application/x-www-form-urlencoded" => scrub_form(&String::from_utf8_lossy(body)),
+                _ => String::from_utf8_lossy(body).to_string(),
+            },
+            mime_type,
+        }),
+        headers_size: -1,
+        body_size: body.map_or(0, |body| body.len() as i64),
+    }
+}
+
+fn har_response(
+    status: StatusCode,
+    version: Version,
+    headers: &HeaderMap,
+    body: &[u8],
+    location: Option<&Url>,
+) -> HarResponse {
+    let (text, encoding) = match std::str::from_utf8(body) {
+        Ok(text) => (scrub_html(text), None),
+        Err(_) => (
+            base64::engine::general_purpose::STANDARD.encode(body),
+            Some("base64".to_string()),
+        ),
+    };
+
+    HarResponse {
+        status: status.as_u16(),
+        status_text: status.canonical_reason().unwrap_or_default().to_string(),
+        http_version: http_version(version),
+        cookies: Vec::new(),
+        headers: har_headers(headers),
+        content: Content {
+            size: body.len() as i64,
+            mime_type: content_type(headers),
+            text,
+            encoding,
+        },
+        redirect_url: location.map(|url| url.to_string()).unwrap_or_default(),
+        headers_size: -1,
+        body_size: body.len() as i64,
+    }
+}
+
+/// Redacts the values of secret inputs of forms (the viewer passes the login on in them).
+///
+/// Keeps the markup as it is otherwise, so replayed forms still parse.
+fn scrub_html(text: &str) -> String {
+    let text = FORM_INPUT_REGEX.replace_all(text, |input: &Captures| {
+        let input = &input[0];
+
+        let is_secret = INPUT_ATTRIBUTE_REGEX
+            .captures_iter(input)
+            .find(|attribute| attribute[2].eq_ignore_ascii_case("name"))
+            .and_then(|attribute| attribute.get(3).or_else(|| attribute.get(4)))
+            .is_some_and(|name| is_secret_field(name.as_str()));
+
+        if !is_secret {
+            return input.to_string();
+        }
+
+        INPUT_ATTRIBUTE_REGEX
+            .replace_all(input, |attribute: &Captures| {
+                let prefix = &attribute[1];
+
+                match (attribute[2].eq_ignore_ascii_case("value"), attribute.get(3)) {
+                    (false, _) => attribute[0].to_string(),
+                    (true, Some(_)) => format!(r#"{prefix}"{REDACTED}""#),
+                    (true, None) => format!("{prefix}'{REDACTED}'"),
+                }
+            })
+            .into_owned()
+    });
+
+    text.to_string()
+}
+
+/// Encodes a value like in a form (`application/x-www-form-urlencoded`).
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                (byte as char).to_string()
+            }
+            b' ' => "+".to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn is_secret_field(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SECRET_FIELDS.iter().any(|secret| name.contains(secret))
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn http_version(version: Version) -> String {
+    format!("{version:?}")
+}
+
+// The parts of the HAR 1.2 format which are recorded (and replayed)
+// (see http://www.softwareishard.com/blog/har-12-spec/)
+//
+// Everything is optional when reading, so captures of browsers can be replayed, too.
+
+#[derive(Serialize, Deserialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Log {
+    version: String,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Creator {
+    name: String,
+    version: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Empty,
+    timings: Timings,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Empty {}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_single_quoted_lti_forms() {
+        let form = "<form action='https://a.digi4school.at/lti' method='post'>\
+            <input name='lti_message_type' value='basic-lti-launch-request'>\
+            <input name='lis_person_name_full' value='Max Mustermann'>\
+            <input name='lis_person_contact_email_primary' value='max@example.at'>\
+            <input name='user_id' value='123456'>\
+            <input name='oauth_signature' value='c2lnbmF0dXJl='>\
+            </form>";
+
+        let scrubbed = scrub_html(form);
+
+        for secret in [
+            "Max Mustermann",
+            "max@example.at",
+            "123456",
+            "c2lnbmF0dXJl=",
+        ] {
+            assert!(!scrubbed.contains(secret), "{secret} leaked: {scrubbed}");
+        }
+
+        // Other fields and the markup are kept (`books::do_book_form_dance` parses it)
+        assert!(
+            scrubbed.contains("<input name='lti_message_type' value='basic-lti-launch-request'>")
+        );
+        assert!(scrubbed.contains("<input name='user_id' value='[redacted]'>"));
+    }
+
+    #[test]
+    fn scrubs_double_quoted_inputs() {
+        let input = r#"<input type="hidden" name="password" value="hunter2">"#;
+
+        assert_eq!(
+            scrub_html(input),
+            r#"<input type="hidden" name="password" value="[redacted]">"#
+        );
+    }
+
+    #[test]
+    fn replays_recordings_with_secret_query_parameters() {
+        let url = Url::parse("https://a.digi4school.at/ebook/1?token=hunter2&page=2").unwrap();
+        let request = Request::new(Method::GET, url.clone());
+
+        let har = Har {
+            log: Log {
+                entries: vec![Entry {
+                    request: har_request(&request),
+                    response: har_response(
+                        StatusCode::OK,
+                        Version::HTTP_11,
+                        &HeaderMap::new(),
+                        b"page 2",
+                        None,
+                    ),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        };
+
+        let json = serde_json::to_string(&har).unwrap();
+        assert!(!json.contains("hunter2"), "{json}");
+
+        let replay = Replay::new(serde_json::from_str(&json).unwrap());
+        let (status, _, _, body) = replay.respond(&Method::GET, &url).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "page 2");
+    }
+
+    #[test]
+    fn keeps_public_inputs() {
+        let input = r#"<input name="title" value='Mathematik 1'>"#;
+
+        assert_eq!(scrub_html(input), input);
+    }
+}
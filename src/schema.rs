@@ -0,0 +1,414 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    books::Img, cache::HttpCache, crawl::ParsedBook, library::Library, login::Credentials,
+    manifest::Manifest, queue::Queue, util, BookComplete,
+};
+
+/// A JSON document written to disk
+///
+/// Saved as `{"kind": ..., "schema_version": ..., "data": ...}`, so older
+/// files can be recognized and upgraded when the format changes.
+pub trait Document: Serialize + DeserializeOwned {
+    /// What the document holds
+    const KIND: &'static str;
+    /// The version written by this build (bump it and extend `upgrade` on changes)
+    const VERSION: u32 = 1;
+    /// Whether to indent the JSON (big, machine-only documents aren't)
+    const PRETTY: bool = true;
+
+    /// Upgrades the data of a document from `version` to `version + 1`.
+    fn upgrade(version: u32, data: Value) -> anyhow::Result<Value> {
+        match version {
+            // Unversioned documents just lack the envelope
+            0 => Ok(data),
+            _ => Err(anyhow::anyhow!(
+                "Can't upgrade {kind} from version {version}",
+                kind = Self::KIND
+            )),
+        }
+    }
+}
+
+impl Document for BookComplete {
+    const KIND: &'static str = "book_data";
+}
+
+impl Document for Vec<ParsedBook> {
+    const KIND: &'static str = "shelf";
+}
+
+impl Document for Vec<Img> {
+    const KIND: &'static str = "images";
+}
+
+impl Document for Library {
+    const KIND: &'static str = "library";
+}
+
+impl Document for Manifest {
+    const KIND: &'static str = "manifest";
+}
+
+impl Document for HttpCache {
+    const KIND: &'static str = "http_cache";
+    const PRETTY: bool = false;
+}
+
+impl Document for Credentials {
+    const KIND: &'static str = "credentials";
+}
+
+impl Document for Queue {
+    const KIND: &'static str = "queue";
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    kind: String,
+    schema_version: u32,
+    data: T,
+}
+
+/// Splits a document into its kind (if known), version and data.
+fn open_envelope(value: Value) -> anyhow::Result<(Option<String>, u32, Value)> {
+    let is_envelope = value.as_object().is_some_and(|object| {
+        object.contains_key("kind")
+            && object.contains_key("schema_version")
+            && object.contains_key("data")
+    });
+
+    if !is_envelope {
+        return Ok((None, 0, value));
+    }
+
+    let envelope: Envelope<Value> = serde_json::from_value(value)?;
+
+    Ok((Some(envelope.kind), envelope.schema_version, envelope.data))
+}
+
+/// Reads a document, upgrading it from older versions.
+///
+/// Returns the document and the version it was stored in.
+fn parse<T: Document>(value: Value) -> anyhow::Result<(T, u32)> {
+    let (kind, version, mut data) = open_envelope(value)?;
+
+    if let Some(kind) = kind.filter(|kind| kind != T::KIND) {
+        return Err(anyhow::anyhow!(
+            "Expected {expected}, but found {kind}",
+            expected = T::KIND
+        ));
+    }
+
+    if version > T::VERSION {
+        return Err(anyhow::anyhow!(
+            "Version {version} of {kind} is newer than this build of d5s supports ({supported}); please update",
+            kind = T::KIND,
+            supported = T::VERSION
+        ));
+    }
+
+    for from in version..T::VERSION {
+        data = T::upgrade(from, data)?;
+    }
+
+    Ok((serde_json::from_value(data)?, version))
+}
+
+/// Loads a document (of any version) from disk.
+pub fn load<T: Document>(path: impl AsRef<Path>) -> anyhow::Result<T> {
+    let path = path.as_ref();
+
+    let read = || -> anyhow::Result<T> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(parse(serde_json::from_reader(file)?)?.0)
+    };
+
+    read().with_context(|| format!("Failed to load {path}", path = path.display()))
+}
+
+/// Saves a document to disk (in the current version).
+///
+/// The document is replaced at once, so it's never left half-written.
+pub fn save<T: Document>(path: impl AsRef<Path>, document: &T) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let envelope = Envelope {
+        kind: T::KIND.to_string(),
+        schema_version: T::VERSION,
+        data: document,
+    };
+
+    let tmp = util::temp_path(path);
+
+    let write = || -> anyhow::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+
+        if T::PRETTY {
+            serde_json::to_writer_pretty(&mut file, &envelope)?;
+        } else {
+            serde_json::to_writer(&mut file, &envelope)?;
+        }
+
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    };
+
+    write().inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+#[derive(Debug, Serialize)]
+/// What `migrate` found a file to be
+pub struct Migration {
+    pub kind: &'static str,
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Upgrades a document to the current version (if it is older).
+fn migrate_as<T: Document>(path: &Path, value: Value, dry_run: bool) -> anyhow::Result<Migration> {
+    let (document, from) = parse::<T>(value)?;
+
+    if from < T::VERSION && !dry_run {
+        save(path, &document)?;
+    }
+
+    Ok(Migration {
+        kind: T::KIND,
+        from,
+        to: T::VERSION,
+    })
+}
+
+/// The keys an unversioned document of each kind has (in the order to try
+/// them, most specific first)
+const OBJECT_KEYS: [(&str, &[&str]); 6] = [
+    (Manifest::KIND, &["book_id", "timestamp", "kind", "entries"]),
+    (
+        BookComplete::KIND,
+        &["timestamp", "book_meta", "parsed_book"],
+    ),
+    (Queue::KIND, &["next_id", "jobs"]),
+    (Credentials::KIND, &["email", "password"]),
+    (Library::KIND, &["books"]),
+    (HttpCache::KIND, &["entries"]),
+];
+
+/// The keys the items of an unversioned list of each kind have
+const ITEM_KEYS: [(&str, &[&str]); 2] = [
+    (
+        <Vec<Img>>::KIND,
+        &["url", "page_number", "img_number", "img_type"],
+    ),
+    (
+        <Vec<ParsedBook>>::KIND,
+        &["url", "code", "id", "cover_url", "title", "expiry_date"],
+    ),
+];
+
+/// Guesses the kind of an unversioned document by the keys it has.
+fn guess_kind(value: &Value) -> Option<&'static str> {
+    fn has_keys(value: &Value, keys: &[&str]) -> bool {
+        value
+            .as_object()
+            .is_some_and(|object| keys.iter().all(|key| object.contains_key(*key)))
+    }
+
+    match value {
+        Value::Object(_) => OBJECT_KEYS
+            .iter()
+            .find(|(_, keys)| has_keys(value, keys))
+            .map(|(kind, _)| *kind),
+        // An empty list could be anything (and needs no upgrade anyway)
+        Value::Array(items) if !items.is_empty() => ITEM_KEYS
+            .iter()
+            .find(|(_, keys)| items.iter().all(|item| has_keys(item, keys)))
+            .map(|(kind, _)| *kind),
+        _ => None,
+    }
+}
+
+/// Upgrades a file to the current version of its document kind.
+///
+/// Returns `None` for files which aren't (recognizable) documents.
+pub fn migrate_file(path: &Path, dry_run: bool) -> anyhow::Result<Option<Migration>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let Ok(value) = serde_json::from_reader::<_, Value>(file) else {
+        return Ok(None);
+    };
+
+    let kind = match open_envelope(value.clone())? {
+        (Some(kind), _, _) => kind,
+        (None, _, value) => match guess_kind(&value) {
+            Some(kind) => kind.to_string(),
+            None => return Ok(None),
+        },
+    };
+
+    let migration = match kind.as_str() {
+        BookComplete::KIND => migrate_as::<BookComplete>(path, value, dry_run),
+        <Vec<ParsedBook>>::KIND => migrate_as::<Vec<ParsedBook>>(path, value, dry_run),
+        <Vec<Img>>::KIND => migrate_as::<Vec<Img>>(path, value, dry_run),
+        Library::KIND => migrate_as::<Library>(path, value, dry_run),
+        Manifest::KIND => migrate_as::<Manifest>(path, value, dry_run),
+        HttpCache::KIND => migrate_as::<HttpCache>(path, value, dry_run),
+        Credentials::KIND => migrate_as::<Credentials>(path, value, dry_run),
+        Queue::KIND => migrate_as::<Queue>(path, value, dry_run),
+        _ => Err(anyhow::anyhow!("Unknown kind of document {kind}")),
+    };
+
+    migration
+        .map(Some)
+        .with_context(|| format!("Failed to migrate {path}", path = path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parsed_book() -> Value {
+        json!({
+            "url": "/ebook/1234",
+            "code": "abcd",
+            "id": "1234",
+            "visibility": "",
+            "cover_url": "https://a.digi4school.at/covers/1234.jpg",
+            "title": "Mathematik 1",
+            "publisher": "Verlag",
+            "expiry_date": "Gültig bis 31.07.2025"
+        })
+    }
+
+    fn version_0_documents() -> Vec<(&'static str, Value)> {
+        vec![
+            (
+                BookComplete::KIND,
+                json!({
+                    "timestamp": "2023-10-15_12-00-00",
+                    "book_meta": {
+                        "title": "Mathematik 1",
+                        "sb_number": "123",
+                        "first_page": "1",
+                        "publisher": "Verlag",
+                        "publisher_web": "",
+                        "publisher_address": "",
+                        "publisher_tel": "",
+                        "publisher_mail": "",
+                        "page_sizes": [[595, 842]]
+                    },
+                    "parsed_book": parsed_book()
+                }),
+            ),
+            (<Vec<ParsedBook>>::KIND, json!([parsed_book()])),
+            (
+                <Vec<Img>>::KIND,
+                json!([{
+                    "url": "https://a.digi4school.at/ebook/1234/1/img/1.png",
+                    "page_number": 1,
+                    "img_number": 1,
+                    "img_type": "Img"
+                }]),
+            ),
+            (
+                Library::KIND,
+                json!({
+                    "books": [{
+                        "parsed_book": parsed_book(),
+                        "book_data": null,
+                        "last_synced": null,
+                        "listed": true
+                    }]
+                }),
+            ),
+            (
+                Manifest::KIND,
+                json!({
+                    "book_id": "1234",
+                    "timestamp": "2023-10-15_12-00-00",
+                    "kind": "Pages",
+                    "entries": [{
+                        "path": "1.svg",
+                        "size": 3,
+                        "sha256": "00",
+                        "url": "https://a.digi4school.at/ebook/1234/1.svg",
+                        "etag": null,
+                        "last_modified": null
+                    }]
+                }),
+            ),
+            (
+                HttpCache::KIND,
+                json!({
+                    "entries": {
+                        "https://a.digi4school.at/ebook/1234/1.svg": {
+                            "etag": "\"1\"",
+                            "last_modified": null,
+                            "sha256": "00",
+                            "size": 3
+                        }
+                    }
+                }),
+            ),
+            (
+                Credentials::KIND,
+                json!({ "email": "max@example.at", "password": "hunter2" }),
+            ),
+            (Queue::KIND, json!({ "next_id": 0, "jobs": [] })),
+        ]
+    }
+
+    #[test]
+    fn guesses_the_kind_of_version_0_documents() {
+        for (kind, value) in version_0_documents() {
+            assert_eq!(guess_kind(&value), Some(kind), "{value}");
+        }
+
+        assert_eq!(guess_kind(&json!([])), None);
+        assert_eq!(guess_kind(&json!({ "cookies": [] })), None);
+    }
+
+    #[test]
+    fn upgrades_version_0_documents() {
+        let dir = std::env::temp_dir().join(format!("d5s-schema-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (kind, value) in version_0_documents() {
+            let path = dir.join(format!("{kind}.json"));
+            std::fs::write(&path, value.to_string()).unwrap();
+
+            let migration = migrate_file(&path, false).unwrap().unwrap();
+            assert_eq!((migration.kind, migration.from), (kind, 0));
+
+            let (stored_kind, version, data) = open_envelope(
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(stored_kind.as_deref(), Some(kind));
+            assert_eq!(version, 1);
+            assert!(data.is_object() || data.is_array());
+
+            // Upgraded documents are left alone
+            let migration = migrate_file(&path, false).unwrap().unwrap();
+            assert_eq!(migration.from, 1);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_documents_of_another_kind() {
+        let envelope = json!({ "kind": "library", "schema_version": 1, "data": { "books": [] } });
+
+        assert!(parse::<Manifest>(envelope.clone()).is_err());
+        assert!(parse::<Library>(envelope).is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+
+use regex::RegexBuilder;
+
+use crate::crawl::ParsedBook;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Visibility {
+    /// Books marked as `bag` on the shelf
+    Bag,
+    /// Books marked as `all` on the shelf
+    All,
+}
+
+// Flags selecting books without any prompts.
+//
+// All given filters have to match; `--all` selects every book.
+// (Not a doc comment, as clap would use it as the about text of every
+// command flattening it.)
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct BookSelection {
+    /// Select the book with this ID (can be repeated).
+    #[clap(long)]
+    pub id: Vec<String>,
+
+    /// Select books whose title matches this (case-insensitive) regex.
+    #[clap(long)]
+    pub title_regex: Option<String>,
+
+    /// Select books whose publisher contains this (case-insensitive) text.
+    #[clap(long)]
+    pub publisher: Option<String>,
+
+    /// Select books with this visibility.
+    #[clap(long, value_enum)]
+    pub visibility: Option<Visibility>,
+
+    /// Select all books.
+    #[clap(long)]
+    pub all: bool,
+
+    /// Select books whose licence expires within this many days.
+    #[clap(long)]
+    pub expiring_within: Option<i64>,
+}
+
+impl BookSelection {
+    /// Whether no selection flags were given at all.
+    pub fn is_empty(&self) -> bool {
+        self.id.is_empty()
+            && self.title_regex.is_none()
+            && self.publisher.is_none()
+            && self.visibility.is_none()
+            && !self.all
+            && self.expiring_within.is_none()
+    }
+
+    /// Selects the matching books (in the order they were crawled in).
+    pub fn select<'a>(&self, books: &'a [ParsedBook]) -> anyhow::Result<Vec<&'a ParsedBook>> {
+        if self.is_empty() {
+            return Err(anyhow::anyhow!("No books selected"));
+        }
+
+        let title_regex = self
+            .title_regex
+            .as_ref()
+            .map(|regex| RegexBuilder::new(regex).case_insensitive(true).build())
+            .transpose()?;
+        let publisher = self.publisher.as_ref().map(|p| p.to_lowercase());
+        let today = chrono::Local::now().date_naive();
+
+        let selected = books
+            .iter()
+            .filter(|book| self.id.is_empty() || self.id.contains(&book.id))
+            .filter(|book| {
+                title_regex
+                    .as_ref()
+                    .is_none_or(|regex| regex.is_match(&book.title))
+            })
+            .filter(|book| {
+                publisher
+                    .as_ref()
+                    .is_none_or(|publisher| book.publisher.to_lowercase().contains(publisher))
+            })
+            .filter(|book| {
+                self.visibility.is_none_or(|visibility| match visibility {
+                    Visibility::Bag => book.visibility == "bag",
+                    Visibility::All => book.visibility == "all",
+                })
+            })
+            .filter(|book| {
+                self.expiring_within.is_none_or(|days| {
+                    book.days_until_expiry(today)
+                        .is_some_and(|remaining| remaining <= days)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if selected.is_empty() {
+            return Err(anyhow::anyhow!("No books match the selection"));
+        }
+
+        Ok(selected)
+    }
+}
+
+/// A set of pages like `1-20,45,100-` (1-based, open ranges run to the end).
+#[derive(Debug, Clone)]
+pub struct PageRanges(Vec<(usize, Option<usize>)>);
+
+impl FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |page: &str| {
+            page.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|page| *page > 0)
+                .ok_or_else(|| format!("Invalid page number: {page:?}"))
+        };
+
+        let ranges = s
+            .split(',')
+            .map(|range| match range.split_once('-') {
+                Some((start, "")) => Ok((parse(start)?, None)),
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+
+                    if start > end {
+                        return Err(format!("Invalid page range: {range:?}"));
+                    }
+
+                    Ok((start, Some(end)))
+                }
+                None => parse(range).map(|page| (page, Some(page))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(PageRanges(ranges))
+    }
+}
+
+impl PageRanges {
+    pub fn contains(&self, page: usize) -> bool {
+        self.0
+            .iter()
+            .any(|(start, end)| page >= *start && end.is_none_or(|end| page <= end))
+    }
+}
+
+/// Whether a page is selected (all pages are, if there are no page ranges).
+pub fn page_selected(pages: Option<&PageRanges>, page: usize) -> bool {
+    pages.is_none_or(|pages| pages.contains(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pages(ranges: &str, count: usize) -> Vec<usize> {
+        let ranges = ranges.parse::<PageRanges>().unwrap();
+
+        (1..=count).filter(|page| ranges.contains(*page)).collect()
+    }
+
+    #[test]
+    fn parses_pages_and_ranges() {
+        assert_eq!(pages("1-3,5", 10), [1, 2, 3, 5]);
+        assert_eq!(pages(" 2 - 3 , 7 ", 10), [2, 3, 7]);
+        assert_eq!(pages("4,4-4", 10), [4]);
+    }
+
+    #[test]
+    fn parses_open_ranges() {
+        assert_eq!(pages("8-", 10), [8, 9, 10]);
+        assert_eq!(pages("1,9-", 10), [1, 9, 10]);
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for ranges in ["", "0", "3-1", "a", "1-b", "-3", "1,,2", "1--2"] {
+            assert!(ranges.parse::<PageRanges>().is_err(), "{ranges:?}");
+        }
+    }
+
+    #[test]
+    fn selects_all_pages_without_ranges() {
+        assert!(page_selected(None, 42));
+        assert!(!page_selected(Some(&"1-3".parse().unwrap()), 4));
+    }
+}
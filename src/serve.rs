@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDateTime;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use regex::RegexBuilder;
+use reqwest::Url;
+
+use crate::{
+    books::{self, BookMeta},
+    config,
+    export::{self, escape_xml, image_media_type},
+    library::{Library, LibraryBook},
+    output::{self, say},
+    schema, BookComplete,
+};
+
+const FEED_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const EPUB_TYPE: &str = "application/epub+zip";
+const HTML_TYPE: &str = "text/html; charset=utf-8";
+
+/// The maximum number of matching pages listed per book when searching
+const MAX_PAGE_HITS: usize = 20;
+
+/// The extracted text of every page of a download run, keyed by its SVG directory
+///
+/// Built on the first search, as reading all pages takes a while.
+type TextIndex = Arc<Mutex<HashMap<PathBuf, Arc<Vec<String>>>>>;
+
+/// Serves the library as a web UI and an OPDS catalog until the process is killed.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let text_index = TextIndex::default();
+
+    let make_service = make_service_fn(move |_conn| {
+        let text_index = text_index.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(text_index.clone(), request)
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+
+    say!("Serving the library at http://{addr}/");
+    say!("Serving the OPDS catalog at http://{addr}/opds");
+
+    output::event(
+        "listening",
+        serde_json::json!({
+            "library": format!("http://{addr}/"),
+            "opds": format!("http://{addr}/opds"),
+        }),
+    );
+
+    server.await?;
+
+    Ok(())
+}
+
+async fn handle_request(
+    text_index: TextIndex,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+
+    // Only used for parsing the query
+    let query = Url::parse(&format!("http://localhost{}", request.uri()))
+        .ok()
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|(key, _)| key == "q")
+                .map(|(_, value)| value.trim().to_string())
+        })
+        .filter(|query| !query.is_empty());
+
+    let response = if request.method() != Method::GET {
+        Ok(status_response(StatusCode::METHOD_NOT_ALLOWED))
+    } else {
+        // Loading the library and reading pages blocks, so keep it off the runtime
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || route(&text_index, &path, query.as_deref()))
+            .await
+            .unwrap_or_else(|e| Err(e.into()))
+    };
+
+    Ok(response.unwrap_or_else(|e| {
+        say!("Failed to serve {path}: {e:#}");
+        status_response(StatusCode::INTERNAL_SERVER_ERROR)
+    }))
+}
+
+/// Answers a request (blocking, see `handle_request`).
+fn route(
+    text_index: &TextIndex,
+    path: &str,
+    query: Option<&str>,
+) -> anyhow::Result<Response<Body>> {
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    // Never let a path segment escape the data directory
+    if segments
+        .iter()
+        .any(|segment| segment.starts_with('.') || segment.contains('\\'))
+    {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+
+    let library = Library::load()?;
+
+    match segments.as_slice() {
+        [] => html_response(library_page(&library, text_index, query)?),
+        ["read", id, "imgs", name] => match library.image_run(id) {
+            Some(img_path) => {
+                let extension = Path::new(name)
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .unwrap_or("png");
+                file_response(&img_path.join(name), image_media_type(extension))
+            }
+            None => Ok(status_response(StatusCode::NOT_FOUND)),
+        },
+        ["read", id, page] => match (library.entry_ref(id), page.parse::<usize>()) {
+            (Some(book), Ok(page)) => match read_page(&library, book, page)? {
+                Some(html) => html_response(html),
+                None => Ok(status_response(StatusCode::NOT_FOUND)),
+            },
+            _ => Ok(status_response(StatusCode::NOT_FOUND)),
+        },
+        ["opds"] => {
+            let feed = opds_feed(&library)?;
+            Ok(Response::builder()
+                .header(CONTENT_TYPE, FEED_TYPE)
+                .body(Body::from(feed))?)
+        }
+        ["covers", id] => match library.entry_ref(id).and_then(|book| book.cover.as_deref()) {
+            Some(cover) => {
+                let extension = cover
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .unwrap_or("jpg");
+                file_response(cover, image_media_type(extension))
+            }
+            None => Ok(status_response(StatusCode::NOT_FOUND)),
+        },
+        ["books", id, "epub"] => match latest_epub(&library, id) {
+            Some(epub) => file_response(&epub, EPUB_TYPE),
+            None => Ok(status_response(StatusCode::NOT_FOUND)),
+        },
+        _ => Ok(status_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+fn html_response(html: String) -> anyhow::Result<Response<Body>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, HTML_TYPE)
+        .body(Body::from(html))?)
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(status.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn file_response(path: &Path, content_type: &str) -> anyhow::Result<Response<Body>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(bytes))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(status_response(StatusCode::NOT_FOUND))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The latest EPUB exported for a book (if any).
+fn latest_epub(library: &Library, id: &str) -> Option<PathBuf> {
+    let recorded = library
+        .entry_ref(id)
+        .and_then(|book| book.epub.clone())
+        .filter(|epub| epub.exists());
+    if recorded.is_some() {
+        return recorded;
+    }
+
+    // Exports from before the library recorded them
+    let mut runs = std::fs::read_dir(config::get().exports_dir().join(id))
+        .ok()?
+        .filter_map(|dir| Some(dir.ok()?.path()))
+        .collect::<Vec<_>>();
+
+    runs.sort();
+
+    runs.into_iter()
+        .rev()
+        .map(|run| run.join(format!("{id}.epub")))
+        .find(|epub| epub.exists())
+}
+
+/// Converts a download timestamp (e.g. `2023-10-15_12-00-00`) for Atom.
+fn atom_date(timestamp: &str) -> Option<String> {
+    NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d_%H-%M-%S")
+        .ok()
+        .map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+fn opds_entry(library: &Library, book: &LibraryBook, book_meta: Option<&BookMeta>) -> String {
+    let id = &book.parsed_book.id;
+    let title = book_meta.map_or(&book.parsed_book.title, |meta| &meta.title);
+    let publisher = book_meta.map_or(&book.parsed_book.publisher, |meta| &meta.publisher);
+
+    let mut entry = vec![
+        format!("<id>urn:d5s:{}</id>", escape_xml(id)),
+        format!("<title>{}</title>", escape_xml(title)),
+        format!(
+            "<updated>{}</updated>",
+            book.last_synced
+                .as_deref()
+                .and_then(atom_date)
+                .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string())
+        ),
+        format!("<dc:publisher>{}</dc:publisher>", escape_xml(publisher)),
+    ];
+
+    if let Some(book_meta) = book_meta.filter(|meta| !meta.sb_number.is_empty()) {
+        entry.push(format!(
+            "<dc:identifier>SBNR:{}</dc:identifier>",
+            escape_xml(&book_meta.sb_number)
+        ));
+        entry.push(format!(
+            "<summary>SB-Nr. {}</summary>",
+            escape_xml(&book_meta.sb_number)
+        ));
+    }
+
+    if let Some(cover) = book.cover.as_deref().filter(|cover| cover.exists()) {
+        let extension = cover
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("jpg");
+        let media_type = image_media_type(extension);
+
+        for rel in [
+            "http://opds-spec.org/image",
+            "http://opds-spec.org/image/thumbnail",
+        ] {
+            entry.push(format!(
+                r#"<link rel="{rel}" href="/covers/{id}" type="{media_type}"/>"#,
+                id = escape_xml(id)
+            ));
+        }
+    }
+
+    if latest_epub(library, id).is_some() {
+        entry.push(format!(
+            r#"<link rel="http://opds-spec.org/acquisition" href="/books/{id}/epub" type="{EPUB_TYPE}"/>"#,
+            id = escape_xml(id)
+        ));
+    }
+
+    format!("<entry>\n{}\n</entry>", entry.join("\n"))
+}
+
+/// Builds the acquisition feed of all downloaded books.
+fn opds_feed(library: &Library) -> anyhow::Result<String> {
+    let mut entries = Vec::new();
+
+    for book in &library.books {
+        // Books which were never downloaded can't be read offline
+        if book.book_data.is_none() {
+            continue;
+        }
+
+        let book_meta = load_book(book)?.map(|complete| complete.book_meta);
+
+        entries.push(opds_entry(library, book, book_meta.as_ref()));
+    }
+
+    let updated = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ");
+
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog">
+<id>urn:d5s:library</id>
+<title>d5s library</title>
+<updated>{updated}</updated>
+<link rel="self" href="/opds" type="{FEED_TYPE}"/>
+<link rel="start" href="/opds" type="{FEED_TYPE}"/>
+{entries}
+</feed>
+"#,
+        entries = entries.join("\n"),
+    ))
+}
+
+fn load_book(book: &LibraryBook) -> anyhow::Result<Option<BookComplete>> {
+    let Some(book_data) = &book.book_data else {
+        return Ok(None);
+    };
+
+    if !book_data.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some(schema::load(book_data)?))
+}
+
+fn svg_path(book: &BookComplete) -> PathBuf {
+    config::get().svg_run(&book.parsed_book.id, &book.timestamp)
+}
+
+/// The text of every page of a book (empty for missing pages).
+fn page_texts(text_index: &TextIndex, book: &BookComplete) -> Arc<Vec<String>> {
+    let svg_path = svg_path(book);
+
+    if let Some(texts) = text_index.lock().unwrap().get(&svg_path) {
+        return texts.clone();
+    }
+
+    let texts = (1..=book.book_meta.page_sizes.len())
+        .map(|page| {
+            std::fs::read_to_string(svg_path.join(format!("{page}.svg")))
+                .map(|svg| books::page_text(&svg))
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    let texts = Arc::new(texts);
+
+    text_index.lock().unwrap().insert(svg_path, texts.clone());
+
+    texts
+}
+
+fn html_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8"/>
+<meta name="viewport" content="width=device-width, initial-scale=1"/>
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em; }}
+nav {{ display: flex; gap: 1em; align-items: center; margin-bottom: 1em; }}
+.grid {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(10em, 1fr)); gap: 1em; }}
+.book {{ text-decoration: none; color: inherit; }}
+.book img {{ width: 100%; aspect-ratio: 3 / 4; object-fit: cover; background: #eee; }}
+.hits {{ font-size: 0.9em; }}
+.page svg {{ width: 100%; height: auto; max-width: 60em; display: block; margin: auto; box-shadow: 0 0 0.5em #999; }}
+</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        title = escape_xml(title)
+    )
+}
+
+/// The library grid, filtered by `query` (matching titles and page text).
+fn library_page(
+    library: &Library,
+    text_index: &TextIndex,
+    query: Option<&str>,
+) -> anyhow::Result<String> {
+    let regex = query
+        .map(|query| {
+            RegexBuilder::new(&regex::escape(query))
+                .case_insensitive(true)
+                .build()
+        })
+        .transpose()?;
+
+    let mut cards = Vec::new();
+
+    for book in &library.books {
+        let Some(complete) = load_book(book)? else {
+            continue;
+        };
+
+        let id = escape_xml(&book.parsed_book.id);
+        let title = &complete.book_meta.title;
+        let mut hits = Vec::new();
+
+        if let Some(regex) = &regex {
+            let texts = page_texts(text_index, &complete);
+
+            for (i, text) in texts.iter().enumerate() {
+                if hits.len() == MAX_PAGE_HITS {
+                    break;
+                }
+
+                let Some(found) = regex.find(text) else {
+                    continue;
+                };
+
+                let start = text[..found.start()]
+                    .char_indices()
+                    .rev()
+                    .nth(40)
+                    .map_or(0, |(i, _)| i);
+                let end = text[found.end()..]
+                    .char_indices()
+                    .nth(40)
+                    .map_or(text.len(), |(i, _)| found.end() + i);
+
+                hits.push(format!(
+                    r#"<li><a href="/read/{id}/{page}">{label}</a>: …{before}<mark>{found}</mark>{after}…</li>"#,
+                    page = i + 1,
+                    label = escape_xml(&export::page_label(&complete.book_meta, i + 1)),
+                    before = escape_xml(&text[start..found.start()]),
+                    found = escape_xml(found.as_str()),
+                    after = escape_xml(&text[found.end()..end]),
+                ));
+            }
+
+            if hits.is_empty() && !regex.is_match(title) {
+                continue;
+            }
+        }
+
+        let cover = match book.cover.as_deref().filter(|cover| cover.exists()) {
+            Some(_) => format!(r#"<img src="/covers/{id}" alt=""/>"#),
+            None => r#"<img alt=""/>"#.to_string(),
+        };
+
+        let hits = if hits.is_empty() {
+            String::new()
+        } else {
+            format!(r#"<ul class="hits">{}</ul>"#, hits.join("\n"))
+        };
+
+        cards.push(format!(
+            r#"<div>
+<a class="book" href="/read/{id}/1">{cover}<div><b>{title}</b></div><div>{publisher}</div></a>
+{hits}
+</div>"#,
+            title = escape_xml(title),
+            publisher = escape_xml(&complete.book_meta.publisher),
+        ));
+    }
+
+    let results = if cards.is_empty() {
+        "<p>No books found.</p>".to_string()
+    } else {
+        format!(r#"<div class="grid">{}</div>"#, cards.join("\n"))
+    };
+
+    let body = format!(
+        r#"<nav>
+<b>Library</b>
+<form action="/"><input type="search" name="q" value="{query}" placeholder="Search titles and text"/> <button>Search</button></form>
+</nav>
+{results}"#,
+        query = escape_xml(query.unwrap_or_default()),
+    );
+
+    Ok(html_page("Library", &body))
+}
+
+/// A page of a book, with its images resolved (`None` if it doesn't exist).
+fn read_page(library: &Library, book: &LibraryBook, page: usize) -> anyhow::Result<Option<String>> {
+    let Some(complete) = load_book(book)? else {
+        return Ok(None);
+    };
+
+    let page_count = complete.book_meta.page_sizes.len();
+
+    if page == 0 || page > page_count {
+        return Ok(None);
+    }
+
+    let Ok(svg) = std::fs::read_to_string(svg_path(&complete).join(format!("{page}.svg"))) else {
+        return Ok(None);
+    };
+
+    let id = &book.parsed_book.id;
+    let img_path = library.image_run(id);
+
+    // Relative to /read/<id>/<page>, so pages and images stay within the book
+    let svg = export::rewrite_page_links(&svg, |page| {
+        (1..=page_count).contains(&page).then(|| page.to_string())
+    });
+    let (svg, _) = export::resolve_images(&svg, page, img_path.as_deref());
+
+    let prev = if page > 1 {
+        format!(r#"<a href="{}">&lt;</a>"#, page - 1)
+    } else {
+        String::new()
+    };
+    let next = if page < page_count {
+        format!(r#"<a href="{}">&gt;</a>"#, page + 1)
+    } else {
+        String::new()
+    };
+
+    let title = &complete.book_meta.title;
+    let label = export::page_label(&complete.book_meta, page);
+
+    let body = format!(
+        r#"<nav>
+<a href="/">Library</a>
+<b>{title}</b>
+{prev}
+<span>{label} ({page}/{page_count})</span>
+{next}
+</nav>
+<div class="page">{svg}</div>"#,
+        title = escape_xml(title),
+        label = escape_xml(&label),
+        svg = export::inline_svg(&svg),
+    );
+
+    Ok(Some(html_page(&format!("{title} – {label}"), &body)))
+}
@@ -0,0 +1,347 @@
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref HTML_TITLE_REGEX: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+    static ref LOGIN_FORM_REGEX: Regex =
+        Regex::new(r#"(?i)type=["']?password|/br/xhr/login|name=["']?passwort?\b"#).unwrap();
+}
+
+/// The extensions an image may have been saved with (see `FileType::extension`)
+pub const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "webp", "gif", "svg"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a downloaded file actually is (going by its first bytes)
+pub enum FileType {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Svg,
+    Html,
+    Unknown,
+}
+
+impl FileType {
+    /// Detects the type of a file from its magic bytes (or else its `Content-Type`).
+    pub fn detect(bytes: &[u8], content_type: Option<&str>) -> Self {
+        if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            return FileType::Png;
+        }
+        if bytes.starts_with(b"\xff\xd8\xff") {
+            return FileType::Jpeg;
+        }
+        if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            return FileType::Gif;
+        }
+        if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+            return FileType::Webp;
+        }
+
+        // Markup: go by the root element (or an HTML doctype)
+        if let Some(root) = markup_root(bytes) {
+            if root.html_doctype {
+                return FileType::Html;
+            }
+
+            match root.name.as_str() {
+                "svg" => return FileType::Svg,
+                "html" | "head" | "body" => return FileType::Html,
+                _ => {}
+            }
+        }
+
+        match content_type.map(|content_type| content_type.to_lowercase()) {
+            Some(content_type) if content_type.starts_with("text/html") => FileType::Html,
+            _ => FileType::Unknown,
+        }
+    }
+
+    pub const fn extension(self) -> Option<&'static str> {
+        match self {
+            FileType::Png => Some("png"),
+            FileType::Jpeg => Some("jpg"),
+            FileType::Gif => Some("gif"),
+            FileType::Webp => Some("webp"),
+            FileType::Svg => Some("svg"),
+            FileType::Html => Some("html"),
+            FileType::Unknown => None,
+        }
+    }
+
+    pub const fn is_image(self) -> bool {
+        matches!(
+            self,
+            FileType::Png | FileType::Jpeg | FileType::Gif | FileType::Webp | FileType::Svg
+        )
+    }
+}
+
+/// The start of a markup document
+struct MarkupRoot {
+    /// The local name of the root element (lowercase)
+    name: String,
+    /// Whether the doctype declares an HTML document
+    html_doctype: bool,
+}
+
+/// Finds the root element of a markup document, skipping the XML prolog,
+/// processing instructions, comments and the doctype (however long they are).
+fn markup_root(bytes: &[u8]) -> Option<MarkupRoot> {
+    /// The bytes after the first `end`
+    fn skip_past<'a>(bytes: &'a [u8], end: &[u8]) -> Option<&'a [u8]> {
+        let position = bytes.windows(end.len()).position(|window| window == end)?;
+        Some(&bytes[position + end.len()..])
+    }
+
+    let mut rest = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    let mut html_doctype = false;
+
+    loop {
+        rest = &rest[rest.iter().position(|b| !b.is_ascii_whitespace())?..];
+
+        if rest.starts_with(b"<?") {
+            rest = skip_past(rest, b"?>")?;
+        } else if rest.starts_with(b"<!--") {
+            rest = skip_past(&rest[4..], b"-->")?;
+        } else if rest.starts_with(b"<!") {
+            // A doctype, which may have an internal subset in brackets
+            let mut in_subset = false;
+            let end = rest.iter().position(|b| match b {
+                b'[' => {
+                    in_subset = true;
+                    false
+                }
+                b']' => {
+                    in_subset = false;
+                    false
+                }
+                b'>' => !in_subset,
+                _ => false,
+            })?;
+
+            let declaration = String::from_utf8_lossy(&rest[2..end]).to_lowercase();
+            html_doctype |= declaration
+                .strip_prefix("doctype")
+                .is_some_and(|doctype| doctype.split_whitespace().next() == Some("html"));
+
+            rest = &rest[end + 1..];
+        } else if rest.starts_with(b"<") {
+            let name = rest[1..]
+                .iter()
+                .take_while(|b| b.is_ascii_alphanumeric() || b"_-.:".contains(b))
+                .map(|b| b.to_ascii_lowercase() as char)
+                .collect::<String>();
+
+            // E.g. `svg:svg`
+            let name = match name.rsplit_once(':') {
+                Some((_, local_name)) => local_name.to_string(),
+                None => name,
+            };
+
+            return (!name.is_empty()).then_some(MarkupRoot { name, html_doctype });
+        } else {
+            return None;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a download is supposed to be
+pub enum Expected {
+    /// The SVG of a page
+    Page,
+    /// An image of any format (named after the format it turns out to be)
+    Image,
+}
+
+impl Expected {
+    /// Guesses what a file is supposed to be from the name it was saved under.
+    pub fn from_path(path: &Path) -> Self {
+        let is_page = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .is_some_and(|stem| stem.parse::<usize>().is_ok());
+
+        if is_page && path.extension().is_some_and(|extension| extension == "svg") {
+            Expected::Page
+        } else {
+            Expected::Image
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Expected::Page => "a page",
+            Expected::Image => "an image",
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The login page was served instead of a file (with a success status)
+pub struct SessionExpired {
+    url: String,
+    expected: Expected,
+}
+
+impl std::fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Got the login page instead of {expected} ({url}); the session expired, please log in again",
+            expected = self.expected.name(),
+            url = self.url
+        )
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+/// Whether an error (or any of its causes) is `SessionExpired`.
+pub fn is_session_expired(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<SessionExpired>())
+}
+
+/// Checks that a download is what it's supposed to be.
+///
+/// Login and error pages are often served with `200 OK`, so the status alone
+/// doesn't tell whether a download worked.
+pub fn check(
+    url: &str,
+    bytes: &[u8],
+    content_type: Option<&str>,
+    expected: Expected,
+) -> anyhow::Result<FileType> {
+    let file_type = FileType::detect(bytes, content_type);
+
+    if file_type == FileType::Html {
+        let html = String::from_utf8_lossy(bytes);
+
+        if LOGIN_FORM_REGEX.is_match(&html) {
+            return Err(SessionExpired {
+                url: url.to_string(),
+                expected,
+            }
+            .into());
+        }
+
+        let title = HTML_TITLE_REGEX
+            .captures(&html)
+            .map(|capture| capture[1].trim().to_string())
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| "untitled".to_string());
+
+        return Err(anyhow::anyhow!(
+            "Got an HTML page ({title:?}) instead of {expected} ({url})",
+            expected = expected.name()
+        ));
+    }
+
+    let fits = match expected {
+        Expected::Page => file_type == FileType::Svg,
+        Expected::Image => file_type.is_image(),
+    };
+
+    if !fits {
+        return Err(anyhow::anyhow!(
+            "Got {description} instead of {expected} ({url})",
+            description = match file_type {
+                FileType::Unknown => "an unknown kind of file".to_string(),
+                file_type => format!("a {} file", file_type.extension().unwrap()),
+            },
+            expected = expected.name()
+        ));
+    }
+
+    Ok(file_type)
+}
+
+/// The path to save a file of `file_type` under: images get the extension of
+/// their actual format (e.g. a JPEG served for `img_1_1.png` becomes `img_1_1.jpg`).
+pub fn typed_path(path: &Path, file_type: FileType, expected: Expected) -> PathBuf {
+    match (expected, file_type.extension()) {
+        (Expected::Image, Some(extension)) => path.with_extension(extension),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Finds an image saved by `typed_path`, whichever format it turned out to be.
+///
+/// Returns its file name.
+pub fn find_image(dir: &Path, stem: &str) -> Option<String> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|extension| format!("{stem}.{extension}"))
+        .find(|name| dir.join(name).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_images_by_magic_bytes() {
+        assert_eq!(
+            FileType::detect(b"\x89PNG\r\n\x1a\n....", None),
+            FileType::Png
+        );
+        assert_eq!(
+            FileType::detect(b"\xff\xd8\xff\xe0..", None),
+            FileType::Jpeg
+        );
+        assert_eq!(FileType::detect(b"GIF89a...", None), FileType::Gif);
+        assert_eq!(
+            FileType::detect(b"RIFF\0\0\0\0WEBPVP8 ", None),
+            FileType::Webp
+        );
+        assert_eq!(
+            FileType::detect(b"RIFF\0\0\0\0WAVE", None),
+            FileType::Unknown
+        );
+    }
+
+    #[test]
+    fn detects_svgs_after_a_long_prolog() {
+        let comment = format!("<!-- {} -->", "x".repeat(10_000));
+        let svg = format!(
+            "\u{feff}<?xml version=\"1.0\"?>\n{comment}\n\
+             <!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"svg11.dtd\" [\n\
+             <!ENTITY ns \"<html>\">\n]>\n<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>"
+        );
+
+        assert_eq!(FileType::detect(svg.as_bytes(), None), FileType::Svg);
+        assert_eq!(
+            FileType::detect(b"<svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"/>", None),
+            FileType::Svg
+        );
+    }
+
+    #[test]
+    fn detects_html() {
+        assert_eq!(
+            FileType::detect(b"<!DOCTYPE html>\n<!-- <svg> -->\n<div>", None),
+            FileType::Html
+        );
+        assert_eq!(
+            FileType::detect(b"  <HTML lang=\"de\"><body><svg></svg>", None),
+            FileType::Html
+        );
+        assert_eq!(
+            FileType::detect(b"Fehler", Some("text/html; charset=utf-8")),
+            FileType::Html
+        );
+    }
+
+    #[test]
+    fn unknown_without_markup() {
+        assert_eq!(FileType::detect(b"", None), FileType::Unknown);
+        assert_eq!(
+            FileType::detect(b"<!-- unterminated", None),
+            FileType::Unknown
+        );
+        assert_eq!(FileType::detect(b"{\"svg\": 1}", None), FileType::Unknown);
+    }
+}
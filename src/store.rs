@@ -0,0 +1,191 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config,
+    manifest::{self, sha256_hex},
+    util,
+};
+
+/// The path of a blob in the content-addressed store (`config::Config::blobs_dir`).
+///
+/// The store holds every downloaded page and image once. The files of a
+/// download run are hard links to the blobs in there, so an unchanged file
+/// doesn't take up space again when a book is re-downloaded.
+pub fn blob_path(sha256: &str) -> PathBuf {
+    let mut path = config::get().blobs_dir();
+    path.push(&sha256[..2]);
+    path.push(sha256);
+    path
+}
+
+/// A lock on the store, held shared while downloads save and link blobs and
+/// exclusively by `gc` (so it never removes a blob which is about to be linked).
+///
+/// Released when dropped.
+pub struct StoreLock {
+    _file: std::fs::File,
+}
+
+impl StoreLock {
+    fn open() -> anyhow::Result<std::fs::File> {
+        let blobs_dir = config::get().blobs_dir();
+        std::fs::create_dir_all(&blobs_dir)?;
+
+        Ok(std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(blobs_dir.join(".lock"))?)
+    }
+
+    /// Waits for a running `gc` to finish.
+    pub async fn shared() -> anyhow::Result<Self> {
+        let file = Self::open()?;
+
+        let file = tokio::task::spawn_blocking(move || file.lock_shared().map(|()| file)).await??;
+
+        Ok(StoreLock { _file: file })
+    }
+
+    /// Fails if files are being downloaded (by any d5s process).
+    fn exclusive() -> anyhow::Result<Self> {
+        let file = Self::open()?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(StoreLock { _file: file }),
+            Err(std::fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+                "Files are being downloaded; run gc again once the downloads are done"
+            )),
+            Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+/// Saves a downloaded file to the store and links it into a run directory.
+///
+/// Returns the SHA-256 of the file.
+pub async fn save(path: impl AsRef<Path>, bytes: &[u8]) -> anyhow::Result<String> {
+    let _lock = StoreLock::shared().await?;
+
+    let path = path.as_ref();
+    let sha256 = sha256_hex(bytes);
+    let blob = blob_path(&sha256);
+
+    if !blob.exists() {
+        tokio::fs::create_dir_all(blob.parent().unwrap()).await?;
+
+        // Write to a temporary file first, so there are never partial blobs
+        let tmp = util::temp_path(&blob);
+        let written = async {
+            tokio::fs::write(&tmp, bytes).await?;
+            tokio::fs::rename(&tmp, &blob).await
+        };
+
+        if let Err(e) = written.await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+    }
+
+    link(path, &sha256).await?;
+
+    Ok(sha256)
+}
+
+/// Links a stored blob into a run directory.
+///
+/// The file only appears once it's complete (and replaces an older one at once).
+pub async fn link(path: impl AsRef<Path>, sha256: &str) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let blob = blob_path(sha256);
+    let tmp = util::temp_path(path);
+
+    let linked = async {
+        if tokio::fs::hard_link(&blob, &tmp).await.is_err() {
+            // E.g. the run directory is on a different file system
+            tokio::fs::copy(&blob, &tmp).await?;
+        }
+
+        // Replaces the link itself (never writing through it, which would change the blob)
+        tokio::fs::rename(&tmp, path).await
+    };
+
+    if let Err(e) = linked.await {
+        let _ = tokio::fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Whether a blob is still linked into a run directory.
+///
+/// Only known on unix; elsewhere gc goes by the manifests and journals alone.
+#[cfg(unix)]
+fn is_linked(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn is_linked(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Removes all blobs which are no longer referenced by any run.
+///
+/// A blob is referenced if a manifest (or the journal of an unfinished run)
+/// lists it for a file which still exists, or if it is still hard-linked into
+/// a run directory. Temporary files left by killed downloads are removed as
+/// well. Fails while files are being downloaded.
+///
+/// Returns the number of removed blobs and the number of freed bytes.
+pub fn gc() -> anyhow::Result<(usize, u64)> {
+    let _lock = StoreLock::exclusive()?;
+
+    let referenced = manifest::all_manifests()?
+        .into_iter()
+        .flat_map(|manifest| manifest.entries)
+        .chain(manifest::all_journal_entries()?)
+        .filter(|entry| entry.path.exists())
+        .map(|entry| entry.sha256)
+        .collect::<HashSet<_>>();
+
+    let mut removed = 0;
+    let mut freed = 0;
+
+    for dir in std::fs::read_dir(config::get().blobs_dir())? {
+        let dir = dir?.path();
+
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for blob in std::fs::read_dir(&dir)? {
+            let blob = blob?;
+            let name = blob.file_name().to_string_lossy().to_string();
+
+            let metadata = blob.metadata()?;
+
+            // Left by a killed download (none is running while gc holds the lock)
+            let is_stale_part = util::is_partial_file(&name);
+
+            if !is_stale_part
+                && (name.starts_with('.') || referenced.contains(&name) || is_linked(&metadata))
+            {
+                continue;
+            }
+
+            std::fs::remove_file(blob.path())?;
+
+            removed += 1;
+            freed += metadata.len();
+        }
+    }
+
+    Ok((removed, freed))
+}
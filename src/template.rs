@@ -0,0 +1,253 @@
+use std::path::{Component, PathBuf};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{books::BookMeta, crawl::ParsedBook};
+
+/// The longest a placeholder may get (in bytes), so paths stay short enough
+const MAX_VALUE_LEN: usize = 85;
+/// The longest a file or directory name may get (in bytes; most file systems allow 255)
+const MAX_NAME_LEN: usize = 200;
+
+/// Names Windows doesn't allow for files (with any extension)
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The values of the placeholders of a template
+pub struct TemplateVars<'a> {
+    pub title: &'a str,
+    pub publisher: &'a str,
+    pub sb_number: &'a str,
+    pub id: &'a str,
+    /// The timestamp of the run (e.g. `2023-10-15_12-00-00`)
+    pub date: &'a str,
+}
+
+impl<'a> TemplateVars<'a> {
+    pub fn new(parsed_book: &'a ParsedBook, book_meta: &'a BookMeta, date: &'a str) -> Self {
+        TemplateVars {
+            title: &book_meta.title,
+            publisher: &book_meta.publisher,
+            sb_number: &book_meta.sb_number,
+            id: &parsed_book.id,
+            date,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "title" => Some(self.title),
+            "publisher" => Some(self.publisher),
+            "sb_number" => Some(self.sb_number),
+            "id" => Some(self.id),
+            "date" => Some(self.date),
+            _ => None,
+        }
+    }
+}
+
+/// Truncates a string to at most `max_len` bytes (at a char boundary).
+fn truncate(text: &str, max_len: usize) -> &str {
+    let end = text
+        .char_indices()
+        .map(|(i, c)| i + c.len_utf8())
+        .take_while(|end| *end <= max_len)
+        .last()
+        .unwrap_or(0);
+
+    &text[..end]
+}
+
+/// Makes a file or directory name safe on every common file system.
+///
+/// Keeps letters of any script (e.g. umlauts), but replaces characters
+/// which are reserved on Windows/macOS/Linux, control characters and
+/// whitespace with `_`.
+pub fn sanitize(name: &str) -> String {
+    // Same name no matter whether it came composed or decomposed (macOS)
+    let name = name.nfc().collect::<String>();
+
+    let mut sanitized = String::new();
+
+    for c in name.chars() {
+        let c = match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() || c.is_whitespace() => '_',
+            c => c,
+        };
+
+        // Collapse runs of replaced characters
+        if c == '_' && sanitized.ends_with('_') {
+            continue;
+        }
+
+        sanitized.push(c);
+    }
+
+    // Windows doesn't allow names ending in a dot or a space
+    let sanitized = truncate(&sanitized, MAX_NAME_LEN)
+        .trim_matches(|c| c == '_' || c == '.' || c == ' ')
+        .to_string();
+
+    let stem = sanitized.split('.').next().unwrap_or_default();
+
+    if sanitized.is_empty() {
+        "_".to_string()
+    } else if RESERVED_NAMES.contains(&stem.to_uppercase().as_str()) {
+        format!("_{sanitized}")
+    } else {
+        sanitized
+    }
+}
+
+/// Fills in the placeholders of a template (like `{id}/{date}_{title}`).
+///
+/// Every placeholder is sanitized, while `/` in the template itself
+/// separates directories. The result is always a relative path.
+pub fn render(template: &str, vars: &TemplateVars) -> anyhow::Result<PathBuf> {
+    let mut path = PathBuf::new();
+
+    for part in template.split('/').filter(|part| !part.is_empty()) {
+        let mut name = String::new();
+        let mut rest = part;
+
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| anyhow::anyhow!("Unclosed placeholder in template {template:?}"))?;
+
+            let placeholder = &rest[start + 1..end];
+            let value = vars.get(placeholder).ok_or_else(|| {
+                anyhow::anyhow!("Unknown placeholder {{{placeholder}}} in template {template:?}")
+            })?;
+
+            name.push_str(&rest[..start]);
+            name.push_str(&sanitize(truncate(value, MAX_VALUE_LEN)));
+            rest = &rest[end + 1..];
+        }
+
+        name.push_str(rest);
+
+        path.push(sanitize(&name));
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(anyhow::anyhow!("Template {template:?} is empty"));
+    }
+
+    // Sanitizing already prevents these, but better be sure
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow::anyhow!(
+            "Template {template:?} leaves its directory"
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Renders the template of a file name and appends its extension.
+///
+/// (`Path::with_extension` would cut titles containing dots short.)
+pub fn render_file(
+    template: &str,
+    vars: &TemplateVars,
+    extension: &str,
+) -> anyhow::Result<PathBuf> {
+    let mut path = render(template, vars)?.into_os_string();
+    path.push(".");
+    path.push(extension);
+
+    Ok(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(title: &str) -> TemplateVars<'_> {
+        TemplateVars {
+            title,
+            publisher: "Verlag",
+            sb_number: "123",
+            id: "1234",
+            date: "2023-10-15_12-00-00",
+        }
+    }
+
+    #[test]
+    fn sanitizes_reserved_characters() {
+        assert_eq!(sanitize("Mathe: 1/2 <neu>?"), "Mathe_1_2_neu");
+        assert_eq!(sanitize("Tab\tand\nnewline"), "Tab_and_newline");
+        assert_eq!(sanitize("Größe & Maß"), "Größe_&_Maß");
+        assert_eq!(sanitize("Ende. "), "Ende");
+    }
+
+    #[test]
+    fn sanitizes_reserved_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize("COM10"), "COM10");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(""), "_");
+    }
+
+    #[test]
+    fn composes_decomposed_umlauts() {
+        assert_eq!(sanitize("Mu\u{308}nchen"), "München");
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        assert_eq!(truncate("aä", 2), "a");
+        assert_eq!(truncate("aä", 3), "aä");
+        assert_eq!(truncate("ä", 1), "");
+
+        let long = "ä".repeat(150);
+        assert_eq!(sanitize(&long), "ä".repeat(MAX_NAME_LEN / 2));
+    }
+
+    #[test]
+    fn renders_templates() {
+        assert_eq!(
+            render("{id}/{date}_{title}", &vars("Mathe: 1/2")).unwrap(),
+            PathBuf::from("1234").join("2023-10-15_12-00-00_Mathe_1_2")
+        );
+        assert_eq!(
+            render_file("{sb_number} {publisher}", &vars(""), "epub").unwrap(),
+            PathBuf::from("123_Verlag.epub")
+        );
+    }
+
+    #[test]
+    fn truncates_placeholders() {
+        let title = "ä".repeat(100);
+        let path = render("{title}", &vars(&title)).unwrap();
+
+        assert_eq!(path, PathBuf::from("ä".repeat(MAX_VALUE_LEN / 2)));
+    }
+
+    #[test]
+    fn never_leaves_the_directory() {
+        assert_eq!(
+            render("{title}", &vars("../..")).unwrap(),
+            PathBuf::from("_")
+        );
+        assert_eq!(
+            render("/{id}/../{title}", &vars("x")).unwrap(),
+            PathBuf::from("1234").join("_").join("x")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(render("{unknown}", &vars("x")).is_err());
+        assert!(render("{title", &vars("x")).is_err());
+        assert!(render("//", &vars("x")).is_err());
+    }
+}
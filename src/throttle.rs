@@ -0,0 +1,298 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use chrono::{Local, NaiveTime};
+use reqwest::Response;
+use serde_json::json;
+
+use crate::{
+    config, interrupt,
+    output::{self, say},
+};
+
+/// Parses a rate like `500K` or `2M` (bytes per second, binary prefixes).
+pub fn parse_rate(rate: &str) -> anyhow::Result<u64> {
+    let rate = rate.trim();
+    let rate = rate.strip_suffix("/s").unwrap_or(rate);
+    let rate = rate.strip_suffix(['B', 'b']).unwrap_or(rate);
+
+    let (number, factor) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1024.0),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1024.0 * 1024.0),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1024.0 * 1024.0 * 1024.0),
+        _ => (rate, 1.0),
+    };
+
+    let bytes = number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * factor)
+        .filter(|bytes| *bytes >= 1.0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid rate {rate:?} (expected e.g. 500K or 2M)"))?;
+
+    Ok(bytes as u64)
+}
+
+/// Parses a daily time window like `16:00-07:30` (which may span midnight).
+///
+/// Empty windows (like `08:00-08:00`) are rejected, as they'd never open.
+pub fn parse_window(window: &str) -> anyhow::Result<(NaiveTime, NaiveTime)> {
+    let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+
+    window
+        .split_once('-')
+        .and_then(|(start, end)| Some((parse(start)?, parse(end)?)))
+        .filter(|(start, end)| start != end)
+        .ok_or_else(|| {
+            anyhow::anyhow!("Invalid time window {window:?} (expected e.g. 16:00-07:30)")
+        })
+}
+
+/// How long until one of the download windows opens (zero while one is open).
+fn time_until_window(now: NaiveTime) -> Duration {
+    let windows = config::get()
+        .download_windows
+        .iter()
+        .filter_map(|window| parse_window(window).ok())
+        .collect::<Vec<_>>();
+
+    time_until_any(&windows, now)
+}
+
+/// How long until one of `windows` opens (zero while one is open, or if there are none).
+fn time_until_any(windows: &[(NaiveTime, NaiveTime)], now: NaiveTime) -> Duration {
+    if windows.is_empty() {
+        return Duration::ZERO;
+    }
+
+    windows
+        .iter()
+        .map(|&(start, end)| {
+            let open = if start <= end {
+                start <= now && now < end
+            } else {
+                now >= start || now < end
+            };
+
+            if open {
+                Duration::ZERO
+            } else {
+                // Negative if the window only opens tomorrow
+                (start - now)
+                    .to_std()
+                    .unwrap_or_else(|_| (start - now + chrono::Duration::days(1)).to_std().unwrap())
+            }
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+/// When the current pause ends (so it's only announced once)
+static PAUSED_UNTIL: Mutex<Option<chrono::DateTime<Local>>> = Mutex::new(None);
+
+/// Waits until downloading is allowed (see `Config::download_windows`).
+///
+/// Fails if interrupted by Ctrl-C while waiting.
+pub async fn wait_for_window() -> anyhow::Result<()> {
+    loop {
+        interrupt::check()?;
+
+        let wait = time_until_window(Local::now().time());
+
+        if wait.is_zero() {
+            *PAUSED_UNTIL.lock().unwrap() = None;
+            return Ok(());
+        }
+
+        {
+            let until = Local::now() + chrono::Duration::from_std(wait).unwrap();
+            let mut paused_until = PAUSED_UNTIL.lock().unwrap();
+
+            if paused_until.is_none() {
+                say!(
+                    "Outside of the download windows; pausing until {until}.",
+                    until = until.format("%H:%M")
+                );
+                output::event("paused", json!({ "until": until.to_rfc3339() }));
+
+                *paused_until = Some(until);
+            }
+        }
+
+        // Wake up now and then, in case the clock jumps (e.g. after suspending)
+        tokio::select! {
+            _ = tokio::time::sleep(wait.min(Duration::from_secs(60))) => {}
+            _ = interrupt::interrupted() => {}
+        }
+    }
+}
+
+/// The bytes which may still be transferred (negative: owed), and when that was
+struct Bucket {
+    available: f64,
+    updated: Instant,
+}
+
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// Waits until `bytes` more bytes may be transferred (see `Config::limit_rate`).
+///
+/// Shared by all downloads, so the limit holds no matter the concurrency.
+async fn consume(bytes: usize) {
+    let Some(rate) = config::get()
+        .limit_rate
+        .as_deref()
+        .and_then(|rate| parse_rate(rate).ok())
+    else {
+        return;
+    };
+    let rate = rate as f64;
+
+    let wait = {
+        let mut bucket = BUCKET.lock().unwrap();
+        let bucket = bucket.get_or_insert_with(|| Bucket {
+            available: rate,
+            updated: Instant::now(),
+        });
+
+        // Allow bursts of up to a second
+        let now = Instant::now();
+        bucket.available =
+            (bucket.available + now.duration_since(bucket.updated).as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+
+        bucket.available -= bytes as f64;
+
+        if bucket.available < 0.0 {
+            Duration::from_secs_f64(-bucket.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    };
+
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Marks a response whose body was already read through `read_body` (and
+/// limited by it), so it isn't counted twice (see `record::send`)
+#[derive(Debug, Clone, Copy)]
+pub struct Throttled;
+
+/// Reads the body of a response, keeping to the bandwidth limit.
+pub async fn read_body(mut response: Response) -> anyhow::Result<Bytes> {
+    let throttled = response.extensions().get::<Throttled>().is_some();
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        if !throttled {
+            consume(chunk.len()).await;
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("1000").unwrap(), 1000);
+        assert_eq!(parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("2M").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_rate("1.5m").unwrap(), 3 * 512 * 1024);
+        assert_eq!(parse_rate(" 1GB/s ").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_rate("100 kb").unwrap(), 100 * 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in ["", "K", "0", "0.5", "-1M", "fast", "5T"] {
+            assert!(parse_rate(rate).is_err(), "{rate:?}");
+        }
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert_eq!(
+            parse_window("09:00-10:30").unwrap(),
+            (time("09:00"), time("10:30"))
+        );
+        assert_eq!(
+            parse_window(" 16:00 - 07:30 ").unwrap(),
+            (time("16:00"), time("07:30"))
+        );
+
+        for window in [
+            "",
+            "16:00",
+            "16:00-",
+            "25:00-07:00",
+            "4pm-7am",
+            "08:00-08:00",
+        ] {
+            assert!(parse_window(window).is_err(), "{window:?}");
+        }
+    }
+
+    #[test]
+    fn windows_within_a_day() {
+        let windows = [parse_window("09:00-10:00").unwrap()];
+
+        assert_eq!(time_until_any(&windows, time("09:00")), Duration::ZERO);
+        assert_eq!(time_until_any(&windows, time("09:59")), Duration::ZERO);
+        assert_eq!(
+            time_until_any(&windows, time("08:30")),
+            Duration::from_secs(30 * 60)
+        );
+        // Closed until tomorrow
+        assert_eq!(
+            time_until_any(&windows, time("10:00")),
+            Duration::from_secs(23 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn windows_crossing_midnight() {
+        let windows = [parse_window("16:00-07:30").unwrap()];
+
+        assert_eq!(time_until_any(&windows, time("16:00")), Duration::ZERO);
+        assert_eq!(time_until_any(&windows, time("23:59")), Duration::ZERO);
+        assert_eq!(time_until_any(&windows, time("00:00")), Duration::ZERO);
+        assert_eq!(time_until_any(&windows, time("07:29")), Duration::ZERO);
+        assert_eq!(
+            time_until_any(&windows, time("07:30")),
+            Duration::from_secs((8 * 60 + 30) * 60)
+        );
+    }
+
+    #[test]
+    fn the_next_of_several_windows() {
+        let windows = [
+            parse_window("22:00-02:00").unwrap(),
+            parse_window("12:00-13:00").unwrap(),
+        ];
+
+        assert_eq!(
+            time_until_any(&windows, time("11:00")),
+            Duration::from_secs(60 * 60)
+        );
+        assert_eq!(
+            time_until_any(&windows, time("14:00")),
+            Duration::from_secs(8 * 60 * 60)
+        );
+        assert_eq!(time_until_any(&[], time("14:00")), Duration::ZERO);
+    }
+}
@@ -1,22 +1,78 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use reqwest::{Client, ClientBuilder};
+use anyhow::Context;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Certificate, Client, ClientBuilder, Proxy,
+};
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
 use tokio::{fs::File, io::AsyncReadExt};
 
+use crate::{config, record};
+
 pub struct ApiClient(pub Client, pub Arc<CookieStoreMutex>);
 
-pub fn make_client_and_store() -> ApiClient {
+/// Builds the client for all requests (using the HTTP settings of the config).
+pub fn make_client(cookie_store: Arc<CookieStoreMutex>) -> anyhow::Result<Client> {
+    let http = &config::get().http;
+
+    let mut builder = ClientBuilder::new()
+        .cookie_provider(cookie_store)
+        .redirect(record::redirect_policy());
+
+    if let Some(proxy) = &http.proxy {
+        if proxy.starts_with("socks") && !cfg!(feature = "socks") {
+            return Err(anyhow::anyhow!(
+                "SOCKS proxies need d5s to be built with `--features socks`"
+            ));
+        }
+
+        builder = builder.proxy(Proxy::all(proxy).context("Invalid proxy")?);
+    }
+
+    for path in &http.root_certificates {
+        let bytes = std::fs::read(path)?;
+        let certificate = Certificate::from_pem(&bytes)
+            .or_else(|_| Certificate::from_der(&bytes))
+            .with_context(|| format!("Invalid certificate {path}", path = path.display()))?;
+
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    if let Some(secs) = http.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = http.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(user_agent) = &http.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &http.headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).context("Invalid header name")?,
+            HeaderValue::from_str(value).context("Invalid header value")?,
+        );
+    }
+
+    Ok(builder.default_headers(headers).build()?)
+}
+
+pub fn make_client_and_store() -> anyhow::Result<ApiClient> {
     let cookie_store = CookieStore::default();
     let cookie_store = CookieStoreMutex::new(cookie_store);
     let cookie_store = Arc::new(cookie_store);
 
-    let client = ClientBuilder::new()
-        .cookie_provider(cookie_store.clone())
-        .build()
-        .unwrap();
-
-    ApiClient(client, cookie_store)
+    Ok(ApiClient(make_client(cookie_store.clone())?, cookie_store))
 }
 
 pub async fn load_cookies_from_json(path: impl AsRef<Path>) -> anyhow::Result<ApiClient> {
@@ -31,27 +87,157 @@ pub async fn load_cookies_from_json(path: impl AsRef<Path>) -> anyhow::Result<Ap
     let cookie_store = reqwest_cookie_store::CookieStoreMutex::new(cookie_store);
     let cookie_store = std::sync::Arc::new(cookie_store);
 
-    let client = ClientBuilder::new()
-        .cookie_provider(cookie_store.clone())
-        .build()
-        .unwrap();
+    Ok(ApiClient(make_client(cookie_store.clone())?, cookie_store))
+}
+
+/// All JSON files inside `dir` (and its subdirectories), sorted.
+pub fn json_files(dir: impl AsRef<Path>) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            files.extend(json_files(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// A unique temporary path next to `path`, to write to before renaming it there.
+///
+/// Hidden and ending in `.part`, so it's never mistaken for a finished file.
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-    Ok(ApiClient(client, cookie_store))
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!(
+        ".{name}.{pid}-{count}.part",
+        pid = std::process::id(),
+        count = COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Temporary files untouched for this long were left by a killed process
+/// (they are written in one go, then renamed)
+const STALE_PARTIAL_FILE_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Whether a file name is one of `temp_path`.
+pub fn is_partial_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".part")
 }
 
-pub fn make_dirs() {
+/// Removes the temporary files (see `temp_path`) this process left in a
+/// directory, and those of other processes which are stale.
+///
+/// Recent ones of other processes are kept, as they may still be writing them.
+pub fn remove_partial_files(dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let Ok(files) = std::fs::read_dir(dir) else {
+        return Ok(());
+    };
+
+    // `.{name}.{pid}-{count}.part`
+    let pid = format!(".{}", std::process::id());
+
+    for file in files {
+        let path = file?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if !is_partial_file(&name) {
+            continue;
+        }
+
+        let is_own = name
+            .strip_suffix(".part")
+            .and_then(|name| name.rsplit_once('-'))
+            .is_some_and(|(name, count)| name.ends_with(&pid) && count.parse::<u64>().is_ok());
+
+        let is_stale = || {
+            std::fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > STALE_PARTIAL_FILE_AGE)
+        };
+
+        if is_own || is_stale() {
+            // Another process may have just removed it
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn make_dirs() -> anyhow::Result<()> {
+    let config = config::get();
+
     let dirs = [
-        "d5s/keys/cookies",
-        "d5s/keys/credentials",
-        "d5s/downloads/meta",
-        "d5s/downloads/assets",
-        "d5s/downloads/pdfs",
-        "d5s/downloads/images",
-        "d5s/downloads/svgs",
-        "d5s/downloads/pages",
+        config.cookies_dir(),
+        config.credentials_dir(),
+        config.meta_dir(),
+        config.svgs_dir(),
+        config.imgs_dir(),
+        config.exports_dir(),
+        config.blobs_dir(),
+        config.covers_dir(),
+        config.cache_dir.clone(),
     ];
 
     for dir in dirs {
-        std::fs::create_dir_all(dir).unwrap();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {dir}", dir = dir.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_own_and_stale_partial_files() {
+        let dir = std::env::temp_dir().join(format!("d5s-partial-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let own = temp_path(&dir.join("1.svg"));
+        let other = dir.join(".1.svg.4294967295-0.part");
+        let stale = dir.join(".2.svg.4294967295-0.part");
+        let finished = dir.join("1.svg");
+
+        for path in [&own, &other, &stale, &finished] {
+            std::fs::write(path, "x").unwrap();
+        }
+
+        let two_hours_ago =
+            std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60);
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+
+        remove_partial_files(&dir).unwrap();
+
+        assert!(!own.exists());
+        assert!(other.exists(), "another process may still be writing it");
+        assert!(!stale.exists());
+        assert!(finished.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }